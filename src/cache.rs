@@ -0,0 +1,447 @@
+//! Cache of the calculated performance attributes, so maps only have to be read and calculated
+//! once. Entries are keyed by map hash, mods, accuracy and misses and hold everything the ratio
+//! and accuracy groupings need, so switching between them or changing the precision doesn't
+//! recalculate anything. Groupings that look at the map itself (sv, density and custom metrics)
+//! still read the map, but their attributes come from and go into the same cache.
+//!
+//! The file is JSON with a schema version. When fields are added the version is bumped, and a
+//! cache with a different version is discarded as a whole instead of mixing old and new entries.
+//! The same happens when the cache was calculated with a different pp algorithm.
+//!
+//! The difficulty curves of the density-curve-shape grouping only depend on the map and mods, so
+//! they are kept next to the entries instead of in them. Caches from before they existed simply
+//! have none yet.
+
+use crate::output::status;
+use crate::{read_listing, Play, Window, PP_VERSION};
+use osu_db::listing::Beatmap;
+use rosu_pp::osu::{OsuDifficultyAttributes, OsuPerformanceAttributes};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    pp_version: Option<String>,
+    entries: HashMap<String, CachedAttributes>,
+    #[serde(default)]
+    curves: HashMap<String, Vec<f64>>,
+}
+
+/// The cache file without looking into the entries, so caches of other versions can still be read
+/// by `cache stats`.
+#[derive(Deserialize)]
+struct AnyCacheFile {
+    version: u32,
+    pp_version: Option<String>,
+    entries: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    curves: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct CachedAttributes {
+    stars: f64,
+    aim: f64,
+    speed: f64,
+    flashlight: f64,
+    slider_factor: f64,
+    ar: f64,
+    od: f64,
+    max_combo: usize,
+    pp: f64,
+    pp_aim: f64,
+    pp_speed: f64,
+    pp_acc: f64,
+    pp_flashlight: f64,
+}
+
+impl From<&OsuPerformanceAttributes> for CachedAttributes {
+    fn from(attributes: &OsuPerformanceAttributes) -> Self {
+        Self {
+            stars: attributes.difficulty.stars,
+            aim: attributes.difficulty.aim,
+            speed: attributes.difficulty.speed,
+            flashlight: attributes.difficulty.flashlight,
+            slider_factor: attributes.difficulty.slider_factor,
+            ar: attributes.difficulty.ar,
+            od: attributes.difficulty.od,
+            max_combo: attributes.difficulty.max_combo,
+            pp: attributes.pp,
+            pp_aim: attributes.pp_aim,
+            pp_speed: attributes.pp_speed,
+            pp_acc: attributes.pp_acc,
+            pp_flashlight: attributes.pp_flashlight,
+        }
+    }
+}
+
+impl From<CachedAttributes> for OsuPerformanceAttributes {
+    fn from(cached: CachedAttributes) -> Self {
+        Self {
+            difficulty: OsuDifficultyAttributes {
+                stars: cached.stars,
+                aim: cached.aim,
+                speed: cached.speed,
+                flashlight: cached.flashlight,
+                slider_factor: cached.slider_factor,
+                ar: cached.ar,
+                od: cached.od,
+                max_combo: cached.max_combo,
+                ..Default::default()
+            },
+            pp: cached.pp,
+            pp_aim: cached.pp_aim,
+            pp_speed: cached.pp_speed,
+            pp_acc: cached.pp_acc,
+            pp_flashlight: cached.pp_flashlight,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Cache {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, CachedAttributes>>,
+    curves: Mutex<HashMap<String, Vec<f64>>>,
+}
+
+impl Cache {
+    /// Reads the cache, starting with an empty one if the file doesn't exist yet or is a cache of
+    /// another version.
+    pub fn load(path: &Path) -> Self {
+        let (entries, curves) = match File::open(path) {
+            Ok(file) => match serde_json::from_reader::<_, CacheFile>(BufReader::new(file)) {
+                Ok(cache)
+                    if cache.version == VERSION
+                        && cache.pp_version.as_deref() == Some(PP_VERSION) =>
+                {
+                    status!("Loaded {} cached calculations", cache.entries.len());
+                    (cache.entries, cache.curves)
+                }
+                Ok(cache) if cache.version != VERSION => {
+                    status!(
+                        "Discarding cache with version {}, the current version is {VERSION}",
+                        cache.version
+                    );
+                    Default::default()
+                }
+                Ok(cache) => {
+                    status!(
+                        "Discarding cache calculated with {}, now using {PP_VERSION}",
+                        cache
+                            .pp_version
+                            .as_deref()
+                            .unwrap_or("an unknown pp version")
+                    );
+                    Default::default()
+                }
+                // Saving would overwrite the file, which might be something else entirely, like
+                // osu!.db after a typo.
+                Err(why) => panic!(
+                    "{} exists but isn't a cache file, refusing to overwrite it. Move or delete it to start a new cache: {why}",
+                    path.display()
+                ),
+            },
+            Err(_) => Default::default(),
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+            curves: Mutex::new(curves),
+        }
+    }
+
+    /// Adds the entries of another cache file that aren't in this one yet, for `--seed-cache`. Unlike
+    /// the cache itself, a seed of another version or pp algorithm is an error instead of being
+    /// discarded, since it was passed on purpose.
+    pub fn seed(&self, path: &Path) {
+        let file = File::open(path)
+            .unwrap_or_else(|why| panic!("Could not open {}: {why}", path.display()));
+        let seed: serde_json::Value = serde_json::from_reader(BufReader::new(file))
+            .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()));
+        if seed.get("entries").is_none() {
+            panic!(
+                "{} is not a cache file, only files written by --cache have all the attributes a map is calculated with (exports don't)",
+                path.display()
+            );
+        }
+
+        let seed: CacheFile = serde_json::from_value(seed)
+            .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()));
+        if seed.version != VERSION {
+            panic!(
+                "{} has cache version {}, but the current version is {VERSION}",
+                path.display(),
+                seed.version
+            );
+        }
+        if seed.pp_version.as_deref() != Some(PP_VERSION) {
+            panic!(
+                "{} was calculated with {}, but this version uses {PP_VERSION}",
+                path.display(),
+                seed.pp_version
+                    .as_deref()
+                    .unwrap_or("an unknown pp version")
+            );
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        for (key, attributes) in seed.entries {
+            entries.entry(key).or_insert(attributes);
+        }
+        let mut curves = self.curves.lock().unwrap();
+        for (key, curve) in seed.curves {
+            curves.entry(key).or_insert(curve);
+        }
+
+        status!(
+            "Seeded the cache with {} calculations from {}",
+            entries.len() - count,
+            path.display()
+        );
+    }
+
+    /// `window` is the part of the map that was calculated, `None` for the whole map.
+    pub fn get(
+        &self,
+        map: &Beatmap,
+        mods: u32,
+        play: Play,
+        window: Option<Window>,
+    ) -> Option<OsuPerformanceAttributes> {
+        let key = key(map, mods, play, window)?;
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .copied()
+            .map(OsuPerformanceAttributes::from)
+    }
+
+    pub fn insert(
+        &self,
+        map: &Beatmap,
+        mods: u32,
+        play: Play,
+        window: Option<Window>,
+        attributes: &OsuPerformanceAttributes,
+    ) {
+        if let Some(key) = key(map, mods, play, window) {
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(key, attributes.into());
+        }
+    }
+
+    /// The difficulty curve of the map with these mods. An empty curve means the map was too short
+    /// to have one.
+    pub fn curve(&self, map: &Beatmap, mods: u32) -> Option<Vec<f64>> {
+        let key = format!("{}:{mods}", map.hash.as_ref()?);
+        self.curves.lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn insert_curve(&self, map: &Beatmap, mods: u32, curve: &[f64]) {
+        if let Some(hash) = &map.hash {
+            let mut curves = self.curves.lock().unwrap();
+            curves.insert(format!("{hash}:{mods}"), curve.to_vec());
+        }
+    }
+
+    pub fn save(self) {
+        let cache = CacheFile {
+            version: VERSION,
+            pp_version: Some(PP_VERSION.to_string()),
+            entries: self.entries.into_inner().unwrap(),
+            curves: self.curves.into_inner().unwrap(),
+        };
+
+        // Written next to the cache and renamed over it like collection.db, so a run that is killed
+        // while saving leaves the previous cache instead of a truncated one that can't be loaded.
+        let mut temporary_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temporary_name.push(".tmp");
+        let temporary = self.path.with_file_name(temporary_name);
+        File::create(&temporary)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer(&mut writer, &cache)?;
+                writer
+                    .into_inner()
+                    .map_err(|why| why.into_error())?
+                    .sync_all()
+            })
+            .and_then(|_| std::fs::rename(&temporary, &self.path))
+            .unwrap_or_else(|why| {
+                let _ = std::fs::remove_file(&temporary);
+                panic!("Could not write {}: {why}", self.path.display())
+            });
+
+        status!("Saved {} calculations to the cache", cache.entries.len());
+    }
+}
+
+/// Maps without a hash can't be told apart, so they are never cached. Full combos keep the key
+/// they had before misses and combo were part of it.
+fn key(map: &Beatmap, mods: u32, play: Play, window: Option<Window>) -> Option<String> {
+    let hash = map.hash.as_ref()?;
+    let mut key = format!("{hash}:{mods}:{}", play.accuracy);
+    match window {
+        Some(Window::Densest(seconds)) => key.push_str(&format!(":{seconds}s")),
+        Some(Window::Opening(seconds)) => key.push_str(&format!(":first{seconds}s")),
+        None => {}
+    }
+    if play.misses > 0 {
+        key.push_str(&format!(":{}x", play.misses));
+    }
+    if let Some(combo) = play.combo {
+        key.push_str(&format!(":{combo}c"));
+    }
+    Some(key)
+}
+
+/// Prints what is in the cache, for the `cache stats` subcommand.
+pub fn stats(path: &Path, osu_path: &Path) {
+    let size = std::fs::metadata(path)
+        .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()))
+        .len();
+    let file =
+        File::open(path).unwrap_or_else(|why| panic!("Could not open {}: {why}", path.display()));
+    let cache: AnyCacheFile = serde_json::from_reader(BufReader::new(file))
+        .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()));
+
+    println!(
+        "{}: {:.1} MB, version {} (current version {VERSION}), calculated with {}",
+        path.display(),
+        size as f64 / 1_000_000.0,
+        cache.version,
+        cache
+            .pp_version
+            .as_deref()
+            .unwrap_or("an unknown pp version")
+    );
+
+    let listing = read_listing(osu_path);
+    let hashes: HashSet<&str> = listing
+        .beatmaps
+        .iter()
+        .filter_map(|map| map.hash.as_deref())
+        .collect();
+    let present = cache
+        .entries
+        .keys()
+        .filter(|key| key.split(':').next().is_some_and(|h| hashes.contains(h)))
+        .count();
+    println!(
+        "{} entries, {present} of them for maps in osu!.db and {} for maps that are gone",
+        cache.entries.len(),
+        cache.entries.len() - present
+    );
+    if !cache.curves.is_empty() {
+        println!("{} difficulty curves", cache.curves.len());
+    }
+
+    // Only the plain calculations of whole maps, windows and plays with misses would count the
+    // same map several times.
+    let mut ratios: BTreeMap<i32, usize> = BTreeMap::new();
+    for (key, value) in cache.entries {
+        if key.split(':').count() != 3 {
+            continue;
+        }
+        let Ok(attributes) = serde_json::from_value::<CachedAttributes>(value) else {
+            continue;
+        };
+        let ratio = attributes.pp_aim / (attributes.pp_aim + attributes.pp_speed);
+        if ratio.is_finite() {
+            *ratios
+                .entry(((ratio * 10.0).floor() as i32).min(9) * 10)
+                .or_default() += 1;
+        }
+    }
+
+    if !ratios.is_empty() {
+        println!("Aim ratios of the cached calculations:");
+        for (ratio, count) in ratios {
+            println!("  {ratio:>2}%-{}%: {count}", ratio + 10);
+        }
+    }
+}
+
+/// Deletes the cache, for the `cache clear` subcommand.
+pub fn clear(path: &Path) {
+    if !path.exists() {
+        println!("{} doesn't exist, nothing to clear", path.display());
+        return;
+    }
+
+    // Only files this tool wrote get deleted, in case the path points somewhere else by mistake.
+    let file =
+        File::open(path).unwrap_or_else(|why| panic!("Could not open {}: {why}", path.display()));
+    if let Err(why) = serde_json::from_reader::<_, AnyCacheFile>(BufReader::new(file)) {
+        panic!(
+            "{} isn't a cache file, refusing to delete it: {why}",
+            path.display()
+        );
+    }
+
+    std::fs::remove_file(path)
+        .unwrap_or_else(|why| panic!("Could not delete {}: {why}", path.display()));
+    println!("Deleted {}", path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_that_arent_a_cache_are_never_overwritten() {
+        let dir = std::env::temp_dir().join(format!("aim_ratio_cache_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("collection.db");
+        std::fs::write(&path, [0x7f, 0x01, 0x02]).unwrap();
+
+        assert!(std::panic::catch_unwind(|| Cache::load(&path)).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), [0x7f, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn failed_save_keeps_the_previous_cache() {
+        let dir = std::env::temp_dir().join(format!("aim_ratio_cache_save_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.json");
+        let _ = std::fs::remove_file(&path);
+        Cache::load(&path).save();
+        let saved = std::fs::read(&path).unwrap();
+
+        // A directory in place of the temporary file makes the save fail halfway.
+        std::fs::create_dir_all(dir.join("cache.json.tmp")).unwrap();
+        assert!(std::panic::catch_unwind(|| Cache::load(&path).save()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), saved);
+        Cache::load(&path);
+    }
+
+    #[test]
+    fn clear_only_deletes_cache_files() {
+        let dir =
+            std::env::temp_dir().join(format!("aim_ratio_cache_clear_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let other = dir.join("osu!.db");
+        std::fs::write(&other, [0x7f, 0x01, 0x02]).unwrap();
+        let cache = dir.join("cache.json");
+        std::fs::write(
+            &cache,
+            r#"{"version": 1, "pp_version": null, "entries": {}}"#,
+        )
+        .unwrap();
+
+        assert!(std::panic::catch_unwind(|| clear(&other)).is_err());
+        assert!(other.exists());
+        clear(&cache);
+        assert!(!cache.exists());
+    }
+}
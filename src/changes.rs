@@ -0,0 +1,137 @@
+//! Differences between the generated collections in `collection.db` before and after a run, used
+//! by `--report-only-changes` and `--dry-run`.
+
+use crate::{is_managed, Args};
+use osu_db::listing::Beatmap;
+use osu_db::{CollectionList, Listing};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// The maps of every collection managed by the tool, ie. starting with the prefix.
+pub fn managed_collections(
+    args: &Args,
+    collections: &CollectionList,
+) -> BTreeMap<String, Vec<String>> {
+    let mut managed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for collection in &collections.collections {
+        let Some(name) = &collection.name else {
+            continue;
+        };
+        if is_managed(args, name) {
+            let hashes = collection.beatmap_hashes.iter().flatten().cloned();
+            managed.entry(name.clone()).or_default().extend(hashes);
+        }
+    }
+
+    managed
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    Created,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize)]
+pub struct CollectionChange {
+    pub name: String,
+    pub kind: ChangeKind,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Every managed collection that gained or lost maps, sorted by name.
+pub fn diff(
+    previous: &BTreeMap<String, Vec<String>>,
+    current: &BTreeMap<String, Vec<String>>,
+) -> Vec<CollectionChange> {
+    let empty = Vec::new();
+    let names: BTreeSet<&String> = previous.keys().chain(current.keys()).collect();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let before = previous.get(name).unwrap_or(&empty);
+            let after = current.get(name).unwrap_or(&empty);
+            let before_set: HashSet<&String> = before.iter().collect();
+            let after_set: HashSet<&String> = after.iter().collect();
+
+            let kind = match (previous.contains_key(name), current.contains_key(name)) {
+                (false, _) => ChangeKind::Created,
+                (_, false) => ChangeKind::Removed,
+                _ => ChangeKind::Changed,
+            };
+            let change = CollectionChange {
+                name: name.clone(),
+                kind,
+                added: after
+                    .iter()
+                    .filter(|h| !before_set.contains(h))
+                    .cloned()
+                    .collect(),
+                removed: before
+                    .iter()
+                    .filter(|h| !after_set.contains(h))
+                    .cloned()
+                    .collect(),
+            };
+
+            // Empty collections that appear or disappear are still a change worth showing.
+            let changed = !change.added.is_empty() || !change.removed.is_empty();
+            (changed || kind != ChangeKind::Changed).then_some(change)
+        })
+        .collect()
+}
+
+/// Prints which maps were added to or removed from each managed collection, skipping the
+/// collections that didn't change.
+pub fn print(changes: &[CollectionChange], listing: &Listing) {
+    if changes.is_empty() {
+        println!("No collections changed");
+        return;
+    }
+
+    let maps: HashMap<&str, &Beatmap> = listing
+        .beatmaps
+        .iter()
+        .filter_map(|map| Some((map.hash.as_deref()?, map)))
+        .collect();
+    let describe = |hash: &str| match maps.get(hash) {
+        Some(map) => format!(
+            "{} - {} [{}]",
+            map.artist_unicode
+                .as_deref()
+                .or(map.artist_ascii.as_deref())
+                .unwrap_or_default(),
+            map.title_unicode
+                .as_deref()
+                .or(map.title_ascii.as_deref())
+                .unwrap_or_default(),
+            map.difficulty_name.as_deref().unwrap_or_default()
+        ),
+        None => format!("unknown map {hash}"),
+    };
+
+    for change in changes {
+        let state = match change.kind {
+            ChangeKind::Created => " (new)",
+            ChangeKind::Removed => " (removed)",
+            ChangeKind::Changed => "",
+        };
+        println!(
+            "{}{state}: +{} -{}",
+            change.name,
+            change.added.len(),
+            change.removed.len()
+        );
+        for hash in &change.added {
+            println!("  + {}", describe(hash));
+        }
+        for hash in &change.removed {
+            println!("  - {}", describe(hash));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Comparison of two `--export-collector` files, to see how changing the settings affects the
+//! generated collections.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct Export {
+    pp_version: Option<String>,
+    collections: Vec<ExportCollection>,
+}
+
+#[derive(Deserialize)]
+struct ExportCollection {
+    name: String,
+    beatmaps: Vec<ExportBeatmap>,
+}
+
+#[derive(Deserialize)]
+struct ExportBeatmap {
+    hash: Option<String>,
+    artist: Option<String>,
+    title: Option<String>,
+    version: Option<String>,
+}
+
+impl ExportBeatmap {
+    fn display_name(&self) -> String {
+        format!(
+            "{} - {} [{}]",
+            self.artist.as_deref().unwrap_or_default(),
+            self.title.as_deref().unwrap_or_default(),
+            self.version.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Comparison {
+    old_map_count: usize,
+    new_map_count: usize,
+    collections: Vec<CollectionDelta>,
+    moved: Vec<MovedMap>,
+    added: Vec<MovedMap>,
+    removed: Vec<MovedMap>,
+}
+
+#[derive(Serialize)]
+struct CollectionDelta {
+    name: String,
+    old_size: usize,
+    new_size: usize,
+}
+
+/// A map with every collection it was in before and after, so maps in one collection per mod
+/// combination are compared as a whole. `from` is empty for added maps and `to` for removed ones.
+#[derive(Serialize)]
+struct MovedMap {
+    hash: String,
+    name: String,
+    from: Vec<String>,
+    to: Vec<String>,
+}
+
+pub fn run(old_path: &Path, new_path: &Path, json: bool) {
+    let old = read_export(old_path);
+    let new = read_export(new_path);
+
+    // On stderr, so the --json output stays a single document.
+    if old.pp_version != new.pp_version {
+        eprintln!(
+            "Warning: the exports were calculated with different pp algorithms ({} and {}), maps can move because of that alone",
+            old.pp_version.as_deref().unwrap_or("unknown"),
+            new.pp_version.as_deref().unwrap_or("unknown")
+        );
+    }
+    let comparison = compare(&old, &new);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&comparison).unwrap());
+    } else {
+        print_comparison(&comparison);
+    }
+}
+
+fn read_export(path: &Path) -> Export {
+    let file =
+        File::open(path).unwrap_or_else(|why| panic!("Could not open {}: {why}", path.display()));
+    serde_json::from_reader(BufReader::new(file))
+        .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()))
+}
+
+/// Maps every hash to the collections it is in and the map itself. With `--mods` a map is in one
+/// collection per mod combination.
+fn index_maps(export: &Export) -> BTreeMap<&str, (BTreeSet<&str>, &ExportBeatmap)> {
+    let mut maps: BTreeMap<&str, (BTreeSet<&str>, &ExportBeatmap)> = BTreeMap::new();
+    for collection in &export.collections {
+        for map in &collection.beatmaps {
+            let Some(hash) = map.hash.as_deref() else {
+                continue;
+            };
+            maps.entry(hash)
+                .or_insert_with(|| (BTreeSet::new(), map))
+                .0
+                .insert(&collection.name);
+        }
+    }
+    maps
+}
+
+fn compare(old: &Export, new: &Export) -> Comparison {
+    let mut sizes: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for collection in &old.collections {
+        sizes.entry(&collection.name).or_default().0 = collection.beatmaps.len();
+    }
+    for collection in &new.collections {
+        sizes.entry(&collection.name).or_default().1 = collection.beatmaps.len();
+    }
+
+    let old_maps = index_maps(old);
+    let new_maps = index_maps(new);
+
+    let none = BTreeSet::new();
+    let moved_map =
+        |hash: &str, map: &ExportBeatmap, from: &BTreeSet<&str>, to: &BTreeSet<&str>| MovedMap {
+            hash: hash.to_string(),
+            name: map.display_name(),
+            from: from.iter().map(|name| name.to_string()).collect(),
+            to: to.iter().map(|name| name.to_string()).collect(),
+        };
+
+    let mut moved = Vec::new();
+    let mut removed = Vec::new();
+    for (hash, (from, map)) in &old_maps {
+        match new_maps.get(hash) {
+            Some((to, _)) if to != from => moved.push(moved_map(hash, map, from, to)),
+            Some(_) => {}
+            None => removed.push(moved_map(hash, map, from, &none)),
+        }
+    }
+
+    let added = new_maps
+        .iter()
+        .filter(|(hash, _)| !old_maps.contains_key(*hash))
+        .map(|(hash, (to, map))| moved_map(hash, map, &none, to))
+        .collect();
+
+    Comparison {
+        old_map_count: old_maps.len(),
+        new_map_count: new_maps.len(),
+        collections: sizes
+            .into_iter()
+            .map(|(name, (old_size, new_size))| CollectionDelta {
+                name: name.to_string(),
+                old_size,
+                new_size,
+            })
+            .collect(),
+        moved,
+        added,
+        removed,
+    }
+}
+
+fn print_comparison(comparison: &Comparison) {
+    println!("Collections:");
+    for delta in &comparison.collections {
+        let change = delta.new_size as i64 - delta.old_size as i64;
+        println!(
+            "  {}: {} -> {} ({change:+})",
+            delta.name, delta.old_size, delta.new_size
+        );
+    }
+
+    if !comparison.moved.is_empty() {
+        println!("Moved maps:");
+        for map in &comparison.moved {
+            println!(
+                "  {}: {} -> {}",
+                map.name,
+                map.from.join(", "),
+                map.to.join(", ")
+            );
+        }
+    }
+
+    if !comparison.added.is_empty() {
+        println!("Added maps:");
+        for map in &comparison.added {
+            println!("  {} in {}", map.name, map.to.join(", "));
+        }
+    }
+
+    if !comparison.removed.is_empty() {
+        println!("Removed maps:");
+        for map in &comparison.removed {
+            println!("  {} from {}", map.name, map.from.join(", "));
+        }
+    }
+
+    println!(
+        "{} maps before, {} maps after: {} moved, {} added, {} removed",
+        comparison.old_map_count,
+        comparison.new_map_count,
+        comparison.moved.len(),
+        comparison.added.len(),
+        comparison.removed.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(collections: &[(&str, &[&str])]) -> Export {
+        Export {
+            pp_version: None,
+            collections: collections
+                .iter()
+                .map(|(name, hashes)| ExportCollection {
+                    name: name.to_string(),
+                    beatmaps: hashes
+                        .iter()
+                        .map(|hash| ExportBeatmap {
+                            hash: Some(hash.to_string()),
+                            artist: None,
+                            title: None,
+                            version: None,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn maps_in_a_collection_per_mod_combination_are_compared_as_a_whole() {
+        let old = export(&[("% NM 60% Aim", &["a", "b"]), ("% DT 70% Aim", &["a", "b"])]);
+        let new = export(&[
+            ("% NM 60% Aim", &["a", "b"]),
+            ("% DT 70% Aim", &["a"]),
+            ("% DT 80% Aim", &["b"]),
+        ]);
+
+        let comparison = compare(&old, &new);
+        assert_eq!(comparison.moved.len(), 1);
+        assert_eq!(comparison.moved[0].hash, "b");
+        assert_eq!(comparison.moved[0].from, ["% DT 70% Aim", "% NM 60% Aim"]);
+        assert_eq!(comparison.moved[0].to, ["% DT 80% Aim", "% NM 60% Aim"]);
+        assert!(comparison.added.is_empty() && comparison.removed.is_empty());
+        assert_eq!((comparison.old_map_count, comparison.new_map_count), (2, 2));
+    }
+}
@@ -0,0 +1,245 @@
+//! Shareable export of the generated collections.
+//!
+//! The file contains the settings the collections were generated with, followed by every
+//! collection and the metadata of its maps:
+//!
+//! ```json
+//! {
+//!   "generator": "aim_ratio_collections 0.1.0",
+//!   "pp_version": "rosu-pp 0.10",
+//!   "settings": { "collection_prefix": "% ", "ratio_precision": 10.0, ... },
+//!   "collections": [
+//!     {
+//!       "name": "% 60% Aim / 40% Tapping",
+//!       "color": "#a75071",
+//!       "beatmaps": [
+//!         {
+//!           "hash": "...",
+//!           "beatmap_id": 1,
+//!           "beatmapset_id": 1,
+//!           "artist": "...",
+//!           "title": "...",
+//!           "version": "...",
+//!           "creator": "...",
+//!           "stars": 5.5
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Maps are identified by their MD5 hash, which is what osu! uses in `collection.db` as well.
+//! The beatmap ids are 0 for maps that were never submitted.
+//!
+//! `color` is only there with `--color-by-bucket`. `collection.db` has no place for colors, so it
+//! is only meant for other tools until osu! supports them.
+//!
+//! `--export-metrics` writes a second kind of export with one row per calculated map and mod
+//! combination, containing every metric the tool knows about no matter what it groups by. It is
+//! CSV for paths ending in `.csv` and a JSON array of [`MapMetrics`] otherwise.
+
+use crate::output::{status, CollectionSize};
+use crate::{listed_mode_stars, Args, BucketKey, GroupedMap, PP_VERSION};
+use osu_db::listing::Beatmap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Serialize)]
+struct CollectorExport<'a> {
+    generator: String,
+    pp_version: &'static str,
+    settings: &'a Args,
+    collections: Vec<ExportCollection<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportCollection<'a> {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    beatmaps: Vec<ExportBeatmap<'a>>,
+}
+
+#[derive(Serialize)]
+struct ExportBeatmap<'a> {
+    hash: Option<&'a str>,
+    beatmap_id: i32,
+    beatmapset_id: i32,
+    artist: Option<&'a str>,
+    title: Option<&'a str>,
+    version: Option<&'a str>,
+    creator: Option<&'a str>,
+    stars: f64,
+}
+
+/// Writes the collections as they were added to collection.db, `created` being the collections in
+/// the order they were added with their final names.
+pub fn write_collector(
+    path: &Path,
+    args: &Args,
+    groups: &BTreeMap<BucketKey, Vec<GroupedMap>>,
+    created: &[CollectionSize],
+    leftovers: &[&Beatmap],
+) {
+    let mut buckets: Vec<i32> = groups.keys().map(|key| key.bucket).collect();
+    buckets.sort_unstable();
+    buckets.dedup();
+
+    let export = CollectorExport {
+        generator: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        pp_version: PP_VERSION,
+        settings: args,
+        collections: created
+            .iter()
+            .map(|collection| ExportCollection {
+                name: collection.name.clone(),
+                color: collection
+                    .key
+                    .filter(|_| args.color_by_bucket)
+                    .map(|key| bucket_color(&buckets, key.bucket)),
+                beatmaps: match collection.key {
+                    Some(key) => groups[&key].iter().map(export_beatmap).collect(),
+                    // The leftovers weren't calculated, so they only have the star rating from
+                    // osu!.db.
+                    None => leftovers
+                        .iter()
+                        .map(|&map| {
+                            export_beatmap(&GroupedMap {
+                                map,
+                                stars: listed_mode_stars(args, map, 0).unwrap_or_default(),
+                            })
+                        })
+                        .collect(),
+                },
+            })
+            .collect(),
+    };
+
+    let file = File::create(path).expect("Could not create export file");
+    serde_json::to_writer_pretty(BufWriter::new(file), &export).expect("Could not write export");
+
+    status!("Exported collections to {}", path.display());
+}
+
+/// Blends from blue to red by the position of the bucket among all buckets, so every mod
+/// combination gets the same colors for the same bucket.
+fn bucket_color(buckets: &[i32], bucket: i32) -> String {
+    const TAPPING: [f64; 3] = [60.0, 120.0, 220.0];
+    const AIM: [f64; 3] = [220.0, 60.0, 60.0];
+
+    let position = buckets.binary_search(&bucket).unwrap_or_default();
+    let t = match buckets.len() {
+        0 | 1 => 1.0,
+        len => position as f64 / (len - 1) as f64,
+    };
+    let [r, g, b] = [0, 1, 2].map(|i| (TAPPING[i] + (AIM[i] - TAPPING[i]) * t).round() as u8);
+
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+fn export_beatmap<'a>(grouped: &GroupedMap<'a>) -> ExportBeatmap<'a> {
+    let map = grouped.map;
+    ExportBeatmap {
+        hash: map.hash.as_deref(),
+        beatmap_id: map.beatmap_id,
+        beatmapset_id: map.beatmapset_id,
+        artist: map
+            .artist_unicode
+            .as_deref()
+            .or(map.artist_ascii.as_deref()),
+        title: map.title_unicode.as_deref().or(map.title_ascii.as_deref()),
+        version: map.difficulty_name.as_deref(),
+        creator: map.creator.as_deref(),
+        stars: grouped.stars,
+    }
+}
+
+/// Everything known about a map after calculating it with one mod combination. Ratios are in
+/// percent, lengths in seconds and AR, OD, CS and BPM are adjusted for the mods.
+#[derive(Serialize)]
+pub struct MapMetrics {
+    pub hash: Option<String>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub mods: String,
+    pub stars: f64,
+    pub pp: f64,
+    pub aim_ratio: f64,
+    pub speed_ratio: f64,
+    pub acc_ratio: f64,
+    pub flashlight_ratio: f64,
+    pub bpm: Option<f64>,
+    pub drain_time: f64,
+    pub total_length: f64,
+    pub ar: f64,
+    pub od: f64,
+    pub cs: f64,
+    pub max_combo: usize,
+}
+
+impl MapMetrics {
+    const CSV_HEADER: &'static str = "hash,artist,title,version,mods,stars,pp,aim_ratio,speed_ratio,acc_ratio,flashlight_ratio,bpm,drain_time,total_length,ar,od,cs,max_combo";
+
+    fn csv_row(&self) -> String {
+        let text = |value: &Option<String>| csv_field(value.as_deref().unwrap_or_default());
+        [
+            text(&self.hash),
+            text(&self.artist),
+            text(&self.title),
+            text(&self.version),
+            self.mods.clone(),
+            format!("{:.4}", self.stars),
+            format!("{:.4}", self.pp),
+            format!("{:.4}", self.aim_ratio),
+            format!("{:.4}", self.speed_ratio),
+            format!("{:.4}", self.acc_ratio),
+            format!("{:.4}", self.flashlight_ratio),
+            self.bpm.map(|bpm| format!("{bpm:.2}")).unwrap_or_default(),
+            format!("{:.1}", self.drain_time),
+            format!("{:.1}", self.total_length),
+            format!("{:.2}", self.ar),
+            format!("{:.2}", self.od),
+            format!("{:.2}", self.cs),
+            self.max_combo.to_string(),
+        ]
+        .join(",")
+    }
+}
+
+pub fn write_metrics(path: &Path, metrics: &[MapMetrics]) {
+    let file = File::create(path)
+        .unwrap_or_else(|why| panic!("Could not create {}: {why}", path.display()));
+    let mut writer = BufWriter::new(file);
+
+    let is_csv = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let written = match is_csv {
+        true => std::iter::once(MapMetrics::CSV_HEADER.to_string())
+            .chain(metrics.iter().map(MapMetrics::csv_row))
+            .try_for_each(|line| writeln!(writer, "{line}")),
+        false => serde_json::to_writer_pretty(&mut writer, metrics).map_err(Into::into),
+    };
+    written
+        .and_then(|_| writer.flush())
+        .unwrap_or_else(|why| panic!("Could not write {}: {why}", path.display()));
+
+    status!(
+        "Exported the metrics of {} maps to {}",
+        metrics.len(),
+        path.display()
+    );
+}
+
+/// Quotes the field if it contains anything that would break the CSV row.
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
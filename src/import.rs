@@ -0,0 +1,187 @@
+//! Writes collections from a file of hashes into `collection.db` without reading or calculating
+//! any maps, to apply a grouping from another install or an earlier run.
+//!
+//! JSON files can either be an object of collection names to hashes, or a file created with
+//! `--export-collector`:
+//!
+//! ```json
+//! {
+//!   "% 60% Aim / 40% Tapping": ["<hash>", "<hash>"],
+//!   "% 70% Aim / 30% Tapping": ["<hash>"]
+//! }
+//! ```
+//!
+//! CSV files have one `collection,hash` line per map. The hash always comes last, so collection
+//! names can contain commas, and a `collection,hash` header line is skipped.
+
+use crate::{
+    has_collection_prefix, read_listing, replace_collection_db, upgrade_collection_version,
+    write_temporary,
+};
+use osu_db::collection::Collection;
+use osu_db::CollectionList;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonImport {
+    Export { collections: Vec<ExportCollection> },
+    Hashes(BTreeMap<String, Vec<String>>),
+}
+
+#[derive(Deserialize)]
+struct ExportCollection {
+    name: String,
+    beatmaps: Vec<ExportBeatmap>,
+}
+
+#[derive(Deserialize)]
+struct ExportBeatmap {
+    hash: Option<String>,
+}
+
+pub fn run(path: &Path, osu_path: &Path, collection_prefix: &str, no_clean: bool) {
+    let collection_path = osu_path.join("collection.db");
+    if !collection_path.exists() {
+        panic!("collection.db was not found");
+    }
+
+    let imported = read_import(path);
+    let listing = read_listing(osu_path);
+    let local: HashSet<&str> = listing
+        .beatmaps
+        .iter()
+        .filter_map(|map| map.hash.as_deref())
+        .collect();
+
+    let mut invalid = 0;
+    let mut missing = 0;
+    let mut new_collections = Vec::new();
+    for (name, hashes) in imported {
+        // Imported collections are managed like generated ones, so the next run replaces them.
+        let name = match has_collection_prefix(&name, collection_prefix) {
+            true => name,
+            false => format!("{collection_prefix}{name}"),
+        };
+
+        let mut beatmap_hashes = Vec::new();
+        for hash in hashes {
+            let hash = hash.trim().to_lowercase();
+            if !is_valid_hash(&hash) {
+                println!("Skipping invalid hash {hash:?} in {name}");
+                invalid += 1;
+                continue;
+            }
+            if !local.contains(hash.as_str()) {
+                missing += 1;
+            }
+            beatmap_hashes.push(Some(hash));
+        }
+
+        println!("Adding {name} with {} maps", beatmap_hashes.len());
+        new_collections.push(Collection {
+            name: Some(name),
+            beatmap_hashes,
+        });
+    }
+
+    if invalid > 0 {
+        println!("Skipped {invalid} invalid hashes");
+    }
+    if missing > 0 {
+        println!(
+            "Warning: {missing} maps are not in osu!.db, osu! only shows them once they are downloaded"
+        );
+    }
+
+    println!("Reading collection.db");
+    let mut collections = CollectionList::from_file(&collection_path).unwrap();
+    upgrade_collection_version(listing.version, &mut collections);
+
+    if !no_clean {
+        let collection_count = collections.collections.len();
+        collections.collections.retain(|c| {
+            c.name
+                .as_deref()
+                .is_none_or(|name| !has_collection_prefix(name, collection_prefix))
+        });
+        println!(
+            "Removed {} collections from previous iteration",
+            collection_count - collections.collections.len()
+        );
+    }
+    collections.collections.extend(new_collections);
+
+    drop(listing);
+    let written = write_temporary(&collection_path, &collections);
+    replace_collection_db(&collection_path, &written);
+
+    println!("Successfully wrote collection.db");
+}
+
+/// The collections in the file. Objects of names to hashes come out sorted by name, the other
+/// formats keep the order of the file.
+fn read_import(path: &Path) -> Vec<(String, Vec<String>)> {
+    let is_csv = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()));
+        return read_csv(&text);
+    }
+
+    let file =
+        File::open(path).unwrap_or_else(|why| panic!("Could not open {}: {why}", path.display()));
+    let import: JsonImport = serde_json::from_reader(BufReader::new(file))
+        .unwrap_or_else(|why| panic!("Could not read {}: {why}", path.display()));
+
+    match import {
+        JsonImport::Export { collections } => collections
+            .into_iter()
+            .map(|c| {
+                let hashes = c.beatmaps.into_iter().filter_map(|m| m.hash).collect();
+                (c.name, hashes)
+            })
+            .collect(),
+        JsonImport::Hashes(collections) => collections.into_iter().collect(),
+    }
+}
+
+fn read_csv(text: &str) -> Vec<(String, Vec<String>)> {
+    let mut collections: Vec<(String, Vec<String>)> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, hash)) = line.rsplit_once(',') else {
+            println!("Skipping line {} without a hash: {line}", index + 1);
+            continue;
+        };
+        let (name, hash) = (name.trim(), hash.trim());
+        if index == 0 && hash.eq_ignore_ascii_case("hash") {
+            continue;
+        }
+
+        match collections
+            .iter_mut()
+            .find(|(existing, _)| existing == name)
+        {
+            Some((_, hashes)) => hashes.push(hash.to_string()),
+            None => collections.push((name.to_string(), vec![hash.to_string()])),
+        }
+    }
+
+    collections
+}
+
+/// osu! identifies maps by the MD5 hash of the `.osu` file, as 32 hex digits.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 32 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
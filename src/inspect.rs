@@ -0,0 +1,123 @@
+//! Prints the difficulty and pp breakdown of a single `.osu` file, to check why a map ended up in
+//! a collection. Every mode is supported, modes without aim and speed show the parts of the pp that
+//! rosu-pp calculates for them.
+//!
+//! osu!standard maps can also be calculated as converts to the other modes. The conversion is
+//! rosu-pp's, which follows osu! closely but isn't guaranteed to match the in-game star rating of
+//! every convert.
+
+use crate::{mods, ConvertMode};
+use rosu_pp::{Beatmap, BeatmapExt, GameMode, PerformanceAttributes};
+use std::path::Path;
+
+pub fn run(path: &Path, mod_combination: &str, accuracy: f64, convert: Option<ConvertMode>) {
+    let mods = mods::parse(mod_combination).unwrap_or_else(|why| panic!("Invalid --mods: {why}"));
+    let map = Beatmap::from_path(path)
+        .unwrap_or_else(|why| panic!("Could not parse {}: {why}", path.display()));
+
+    println!(
+        "{} with {} at {accuracy}%",
+        path.display(),
+        mods::name(mods)
+    );
+
+    let mut calculation = map.pp().mods(mods).accuracy(accuracy);
+    if let Some(convert) = convert {
+        if map.mode != GameMode::Osu {
+            panic!("Only osu!standard maps can be converted");
+        }
+        calculation = calculation.mode(convert.game_mode());
+    }
+
+    for line in breakdown(&calculation.calculate()) {
+        println!("{line}");
+    }
+}
+
+/// The lines describing the attributes, with the parts of the pp the mode has.
+fn breakdown(attributes: &PerformanceAttributes) -> Vec<String> {
+    match attributes {
+        PerformanceAttributes::Osu(attributes) => {
+            let difficulty = &attributes.difficulty;
+            vec![
+                "Mode: osu!standard".to_string(),
+                format!("Stars: {:.2}", difficulty.stars),
+                format!(
+                    "Aim: {:.2}, Speed: {:.2}, Flashlight: {:.2}",
+                    difficulty.aim, difficulty.speed, difficulty.flashlight
+                ),
+                format!("Total pp: {:.2}", attributes.pp),
+                format!(
+                    "Aim pp: {:.2}, Speed pp: {:.2}, Accuracy pp: {:.2}, Flashlight pp: {:.2}",
+                    attributes.pp_aim,
+                    attributes.pp_speed,
+                    attributes.pp_acc,
+                    attributes.pp_flashlight
+                ),
+            ]
+        }
+        PerformanceAttributes::Taiko(attributes) => {
+            let difficulty = &attributes.difficulty;
+            vec![
+                "Mode: osu!taiko".to_string(),
+                format!("Stars: {:.2}", difficulty.stars),
+                format!(
+                    "Stamina: {:.2}, Rhythm: {:.2}, Colour: {:.2}, Peak: {:.2}",
+                    difficulty.stamina, difficulty.rhythm, difficulty.colour, difficulty.peak
+                ),
+                format!("Total pp: {:.2}", attributes.pp),
+                format!(
+                    "Difficulty pp: {:.2}, Accuracy pp: {:.2}",
+                    attributes.pp_difficulty, attributes.pp_acc
+                ),
+            ]
+        }
+        PerformanceAttributes::Mania(attributes) => vec![
+            "Mode: osu!mania".to_string(),
+            format!("Stars: {:.2}", attributes.difficulty.stars),
+            format!("Total pp: {:.2}", attributes.pp),
+            format!("Difficulty pp: {:.2}", attributes.pp_difficulty),
+        ],
+        // Catch pp isn't split into parts, so there is nothing more to show than the total.
+        PerformanceAttributes::Catch(attributes) => vec![
+            "Mode: osu!catch".to_string(),
+            format!("Stars: {:.2}", attributes.difficulty.stars),
+            format!("Total pp: {:.2}", attributes.pp),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::osu_file;
+
+    fn inspect(mode: u8) -> Vec<String> {
+        let map = Beatmap::from_bytes(osu_file(mode, 200, 200, 150.0).as_bytes()).unwrap();
+        breakdown(&map.pp().accuracy(99.0).calculate())
+    }
+
+    #[test]
+    fn every_mode_shows_its_own_breakdown() {
+        let standard = inspect(0);
+        assert_eq!(standard[0], "Mode: osu!standard");
+        assert!(standard[2].starts_with("Aim: ") && standard[4].starts_with("Aim pp: "));
+
+        let taiko = inspect(1);
+        assert_eq!(taiko[0], "Mode: osu!taiko");
+        assert!(taiko[2].starts_with("Stamina: ") && taiko[4].starts_with("Difficulty pp: "));
+
+        let catch = inspect(2);
+        assert_eq!(catch.len(), 3);
+        assert_eq!(catch[0], "Mode: osu!catch");
+
+        let mania = inspect(3);
+        assert_eq!(mania[0], "Mode: osu!mania");
+        assert!(mania[3].starts_with("Difficulty pp: "));
+
+        for lines in [standard, taiko, catch, mania] {
+            assert!(lines.iter().any(|line| line.starts_with("Total pp: ")));
+            assert_ne!(lines[1], "Stars: 0.00");
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Limits how many `.osu` files are read at the same time, independent of how many threads
+//! calculate pp. Hard drives slow down a lot when many files are read at once, while the pp
+//! calculation itself only needs the CPU.
+
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+
+pub struct IoLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl IoLimit {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            available: Mutex::new(concurrency),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Reads the whole file, waiting until fewer than the configured number of reads are running.
+    pub fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        let mut available = self
+            .released
+            .wait_while(self.available.lock().unwrap(), |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        drop(available);
+
+        let bytes = std::fs::read(path);
+
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+
+        bytes
+    }
+}
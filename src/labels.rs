@@ -0,0 +1,110 @@
+//! Collection names for buckets from a `--labels` file, so the names can be chosen freely and
+//! shared without changing how the maps are grouped.
+//!
+//! Every label either names a single bucket by its lower bound, or a range of buckets from `from`
+//! up to but excluding `to`. The bounds are in the unit of the grouping, eg. percent for the aim
+//! ratio or stars for diff-spread, and length bands are numbered from 0. All buckets in a range
+//! end up in one collection. Collections with an `order` come first in collection.db, sorted by it:
+//!
+//! ```json
+//! {
+//!   "labels": [
+//!     { "from": 0, "to": 40, "name": "Tapping", "order": 3 },
+//!     { "from": 40, "to": 70, "name": "Balanced", "order": 2 },
+//!     { "bucket": 90, "name": "Jumps", "order": 1 }
+//!   ]
+//! }
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+/// Bucket bounds come out of a multiplication with the precision, so they are compared with some
+/// room for rounding.
+const EPSILON: f64 = 1e-9;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Labels {
+    labels: Vec<Label>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Label {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bucket: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<i64>,
+}
+
+impl Label {
+    /// The bounds the label covers, a single bucket being a range of its own.
+    fn range(&self) -> (f64, f64) {
+        match self.bucket {
+            Some(bucket) => (bucket, bucket + EPSILON),
+            None => (self.from.unwrap(), self.to.unwrap()),
+        }
+    }
+
+    fn contains(&self, lower: f64) -> bool {
+        match self.bucket {
+            Some(bucket) => (lower - bucket).abs() < EPSILON,
+            None => lower >= self.from.unwrap() - EPSILON && lower < self.to.unwrap() - EPSILON,
+        }
+    }
+}
+
+impl Labels {
+    /// The index and label of the bucket with this lower bound, if any label covers it.
+    pub fn find(&self, lower: f64) -> Option<(usize, &Label)> {
+        self.labels
+            .iter()
+            .enumerate()
+            .find(|(_, label)| label.contains(lower))
+    }
+
+    /// Labels in the order of the file, for reporting the ones no map fell into.
+    pub fn iter(&self) -> impl Iterator<Item = &Label> {
+        self.labels.iter()
+    }
+}
+
+/// Reads and validates the file, for the `--labels` value parser.
+pub fn read(path: &str) -> Result<Labels, String> {
+    let text = std::fs::read_to_string(path).map_err(|why| format!("could not read it: {why}"))?;
+    let labels: Labels = serde_json::from_str(&text).map_err(|why| why.to_string())?;
+
+    for label in &labels.labels {
+        if label.name.trim().is_empty() {
+            return Err("every label needs a name".to_string());
+        }
+        match (label.bucket, label.from, label.to) {
+            (Some(_), None, None) => {}
+            (None, Some(from), Some(to)) if from < to => {}
+            (None, Some(_), Some(_)) => {
+                return Err(format!("{}: `from` has to be below `to`", label.name));
+            }
+            _ => {
+                return Err(format!(
+                    "{}: a label needs either `bucket` or both `from` and `to`",
+                    label.name
+                ));
+            }
+        }
+    }
+
+    for (index, label) in labels.labels.iter().enumerate() {
+        let (from, to) = label.range();
+        if let Some(other) = labels.labels[index + 1..].iter().find(|other| {
+            let (other_from, other_to) = other.range();
+            from < other_to && other_from < to
+        }) {
+            return Err(format!("{} and {} overlap", label.name, other.name));
+        }
+    }
+
+    Ok(labels)
+}
@@ -1,171 +1,4352 @@
-use clap::Parser;
+mod cache;
+mod changes;
+mod compare;
+mod export;
+mod import;
+mod inspect;
+mod io_limit;
+mod labels;
+mod mods;
+mod osz;
+mod output;
+mod running;
+
+use crate::cache::Cache;
+use crate::export::MapMetrics;
+use crate::io_limit::IoLimit;
+use crate::labels::Labels;
+use crate::output::{detail, problem, skipped, status, CollectionSize, Summary};
+use clap::{Parser, Subcommand, ValueEnum};
+use evalexpr::{ContextWithMutableVariables, HashMapContext, Node, Value};
 use osu_db::collection::Collection;
 use osu_db::listing::Beatmap;
-use osu_db::{CollectionList, Listing, Mode};
-use rosu_pp::{BeatmapExt, PerformanceAttributes};
-use std::collections::HashMap;
-use std::path::Path;
+use osu_db::{CollectionList, Listing, ModSet, Mode};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use regex::Regex;
+use rosu_pp::beatmap::DifficultyPoint;
+use rosu_pp::osu::{OsuPP, OsuPerformanceAttributes, OsuStars};
+use rosu_pp::{BeatmapExt, GameMode, Mods, PerformanceAttributes};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::time::Instant;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Parser, Debug, Serialize)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 /// Create osu! collections based on aim/tapping ratio
 struct Args {
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
     #[arg(default_value = ".")]
     /// Path to the osu! directory
-    osu_path: String,
+    #[serde(serialize_with = "serialize_path_lossy")]
+    osu_path: PathBuf,
+
+    #[arg(long, conflicts_with_all = ["no_storyboard", "no_video"])]
+    /// Read the maps from the .osz archives in this folder instead of osu!.db. The storyboard and
+    /// video filters look at the folders in Songs, which archives don't have yet
+    scan_osz: Option<PathBuf>,
+
+    #[arg(long)]
+    /// A folder with edited .osu files named `<hash>.osu`, used instead of the maps in Songs
+    osu_override_dir: Option<PathBuf>,
 
     #[arg(long, default_value = "% ")]
     /// The prefix to add to each collection
     collection_prefix: String,
 
+    #[arg(long, value_parser = Regex::new)]
+    /// Remove the collections matching this regex before adding the new ones, instead of the ones
+    /// starting with --collection-prefix (eg. "^(% |aim )" to clean up an old prefix as well)
+    #[serde(serialize_with = "serialize_regex")]
+    clean_regex: Option<Regex>,
+
+    #[arg(long, requires = "yes")]
+    /// Allow an empty --collection-prefix, which treats every collection as generated by the tool
+    /// and removes all of them
+    allow_empty_prefix: bool,
+
+    #[arg(long)]
+    /// Confirm options that can delete collections, like --allow-empty-prefix or a --clean-regex
+    /// matching collections without the prefix
+    yes: bool,
+
     #[arg(long, default_value = "10.0")]
     /// The multiples of which the aim ratio is grouped by (eg. precision 5 => groups of 50%, 55%, 60%...)
     ratio_precision: f64,
 
+    #[arg(long, default_value = "1.0")]
+    /// The star rating band width when grouping by stars-and-ratio or first-seconds (eg. precision 0.5 => bands of 5★, 5.5★, 6★...)
+    star_precision: f64,
+
     #[arg(long, default_value = "4.0")]
     /// The minimum star rating to consider for collections (will speed up the process a lot)
     min_star_rating: f64,
+
+    #[arg(long, value_name = "NAME")]
+    /// Put the maps that only --min-star-rating filtered out into one collection with this name
+    /// (after the prefix)
+    leftovers: Option<String>,
+
+    #[arg(long)]
+    /// The minimum total pp at 99% accuracy, checked after calculating each map
+    min_pp: Option<f64>,
+
+    #[arg(long, default_value = "0.9")]
+    /// Warn when less than this fraction of the standard maps have a star rating in osu!.db
+    min_ratings_computed: f64,
+
+    #[arg(long)]
+    /// Don't warn about star ratings that osu! hasn't calculated yet
+    no_ratings_check: bool,
+
+    #[arg(long, value_enum, conflicts_with = "ratio_denominator")]
+    /// Normalize the aim ratio relative to the map's overall difficulty instead of using the raw aim/speed pp split
+    normalize: Option<Normalize>,
+
+    #[arg(long)]
+    /// Skip maps with a storyboard (.osb file) in their folder
+    no_storyboard: bool,
+
+    #[arg(long)]
+    /// Skip maps with a video in their folder
+    no_video: bool,
+
+    #[arg(long)]
+    /// The minimum drain time in seconds (osu!.db drain time, which excludes breaks)
+    min_drain: Option<u32>,
+
+    #[arg(long)]
+    /// The maximum drain time in seconds (osu!.db drain time, which excludes breaks)
+    max_drain: Option<u32>,
+
+    #[arg(long)]
+    /// The minimum total length in seconds (osu!.db total time, which includes breaks)
+    min_length: Option<u32>,
+
+    #[arg(long)]
+    /// The maximum total length in seconds (osu!.db total time, which includes breaks)
+    max_length: Option<u32>,
+
+    #[arg(long, value_name = "FRACTION")]
+    /// Skip maps where the breaks take up more than this fraction of the time from the first to
+    /// the last note (eg. 0.3), read from the break periods in the .osu file
+    max_break_fraction: Option<f64>,
+
+    #[arg(long, conflicts_with = "group_by")]
+    /// Group by a custom arithmetic expression instead of the aim ratio, bucketed by --ratio-precision.
+    /// Available variables: aim, speed, fl (pp values), stars, bpm, length (seconds), ar, od, cs, combo
+    metric_expr: Option<String>,
+
+    #[arg(long)]
+    /// Export the generated collections with map metadata and the used settings to a shareable JSON file
+    export_collector: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Export every metric of every calculated map and mod combination, as CSV if the path ends
+    /// in .csv and as JSON otherwise
+    export_metrics: Option<PathBuf>,
+
+    #[arg(long, requires = "export_collector")]
+    /// Give every collection in the export a color, from blue for the most tapping heavy to red
+    /// for the most aim heavy bucket
+    color_by_bucket: bool,
+
+    #[arg(long)]
+    /// Don't read collection.db back after writing to check that it was written correctly
+    no_verify_write: bool,
+
+    #[arg(long)]
+    /// Don't warn when osu! is running, which overwrites collection.db when it is closed
+    ignore_running: bool,
+
+    #[arg(long)]
+    /// Print the aim and speed pp, the value and the resulting group of every map
+    explain: bool,
+
+    #[arg(long, value_enum, default_value = "text")]
+    /// What to print while running
+    output_format: OutputFormat,
+
+    #[arg(long)]
+    /// Append the star rating range of each collection's maps to its name
+    name_include_stars: bool,
+
+    #[arg(long, default_value = "0")]
+    /// Decimal places of the percentages in collection names, for ratio precisions below 1
+    name_decimals: usize,
+
+    #[arg(long)]
+    /// Skip difficulties whose name contains this text, case-insensitive (can be repeated)
+    exclude_version_contains: Vec<String>,
+
+    #[arg(long)]
+    /// Only include difficulties whose name contains this text, case-insensitive (can be repeated)
+    include_version_contains: Vec<String>,
+
+    #[arg(long)]
+    /// Keep the collections generated by previous runs instead of removing them first.
+    /// Without --merge this can create duplicate collections
+    no_clean: bool,
+
+    #[arg(long, value_enum, default_value = "end")]
+    /// Where to put the generated collections relative to the other collections
+    collection_order: CollectionOrder,
+
+    #[arg(long, value_enum, default_value = "rename")]
+    /// What to do when a generated collection has the same name as a collection that wasn't
+    /// created by the tool
+    on_name_collision: NameCollision,
+
+    #[arg(long)]
+    /// Add maps to an existing collection with the same name instead of creating a second one
+    merge: bool,
+
+    #[arg(long, requires = "merge")]
+    /// Also remove the maps that no longer belong in a collection that is merged into
+    prune_merge: bool,
+
+    #[arg(long, value_enum, default_value = "aim")]
+    /// What to group the maps by
+    group_by: GroupBy,
+
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["metric_expr", "cache", "window_metric", "export_metrics"]
+    )]
+    /// Group the maps of this mode instead of the standard maps. They can be grouped by
+    /// length-band and density, taiko and catch also by sv, and catch and mania by cs (the
+    /// keycount in mania)
+    mode: Option<ConvertMode>,
+
+    #[arg(long, requires = "mode")]
+    /// Also group the standard maps as converts to --mode
+    include_converts: bool,
+
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["metric_expr", "only_buckets"])]
+    /// Put every map that passes the filters into one collection with this name (after the prefix)
+    /// instead of grouping them
+    single_collection: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = labels::read,
+        conflicts_with_all = ["single_collection", "merge_threshold"]
+    )]
+    /// A JSON file naming buckets or ranges of buckets, which replace the generated names. All
+    /// buckets in a range become one collection
+    labels: Option<Labels>,
+
+    #[arg(long, value_delimiter = ',', default_value = "90,180,300")]
+    /// Band boundaries in seconds when grouping by length-band
+    length_bands: Vec<u32>,
+
+    #[arg(long, value_enum, default_value = "drain")]
+    /// Which length to use when grouping by length-band
+    length_source: LengthSource,
+
+    #[arg(long)]
+    /// Skip maps without a known length instead of collecting them when grouping by length-band
+    skip_unknown_length: bool,
+
+    #[arg(long, default_value = "0.5")]
+    /// The size of each slider velocity group when grouping by sv
+    sv_precision: f64,
+
+    #[arg(long, default_value = "0.5")]
+    /// The size of each circle size group when grouping by cs
+    cs_precision: f64,
+
+    #[arg(long, default_value = "2.0")]
+    /// The size of each note density group in notes per second when grouping by density
+    density_precision: f64,
+
+    #[arg(long, default_value = "2.0")]
+    /// The size of each group in percent of the pp lost to a single miss when grouping by miss-sensitivity
+    miss_precision: f64,
+
+    #[arg(long, default_value = "1.0")]
+    /// The size of each group in stars when grouping by diff-spread
+    spread_precision: f64,
+
+    #[arg(long, default_value = "0.25")]
+    /// How much harder or easier the last quarter has to be than the first, as a fraction of the
+    /// average strain, to count as ascending or descending when grouping by density-curve-shape
+    curve_sensitivity: f64,
+
+    #[arg(long, default_value = "0.35")]
+    /// The standard deviation of the strain, as a fraction of the average, above which a map
+    /// without a trend counts as bursty instead of flat when grouping by density-curve-shape
+    curve_burstiness: f64,
+
+    #[arg(long, default_value = "1.0")]
+    /// The length of the sliding window in seconds used to measure note density
+    density_window: f64,
+
+    #[arg(long, default_value = "90.0")]
+    /// Which percentile of the local note densities a map is grouped by
+    density_percentile: f64,
+
+    #[arg(long)]
+    /// Never remove the collection with exactly this name, even if it starts with the prefix (can be repeated)
+    preserve: Vec<String>,
+
+    #[arg(long, value_delimiter = ',', value_name = "BUCKETS")]
+    /// Only create and replace the buckets starting at these values (eg. 60,70 for the 60% and 70% aim collections), the other generated collections are left alone
+    only_buckets: Vec<f64>,
+
+    #[arg(long, value_delimiter = ',')]
+    /// Mod combinations to calculate, eg. "HDDT" or "NM,HD,DT". Every combination gets its own set of collections.
+    /// "all" expands to NM, EZ, HD, HR, DT, HDHR, HDDT and HRDT
+    mods: Vec<String>,
+
+    #[arg(long, default_value = "0")]
+    /// Calculate the pp for a play with this many misses instead of a full combo
+    misses: usize,
+
+    #[arg(long)]
+    /// Calculate the pp for a play with this highest combo instead of a full combo, maps with a
+    /// lower max combo are still calculated as a full combo
+    combo: Option<usize>,
+
+    #[arg(long, value_enum, default_value = "aim-speed")]
+    /// What the aim pp is divided by to get the aim ratio
+    ratio_denominator: RatioDenominator,
+
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    /// Whether the aim part of the ratio includes the aim needed for sliders
+    aim_includes_sliders: bool,
+
+    #[arg(long)]
+    /// Scale the aim ratio by the drain time, so long maps land in more aim heavy groups than short
+    /// maps with the same ratio
+    length_weighted_ratio: bool,
+
+    #[arg(long, default_value = "0.25", requires = "length_weighted_ratio")]
+    /// How strongly the length changes the aim ratio, the ratio is multiplied by
+    /// (drain time / 3 minutes) ^ strength
+    length_weight_strength: f64,
+
+    #[arg(long, value_name = "SECONDS")]
+    /// Calculate the aim ratio over only the densest part of the map with this length, instead of
+    /// the whole map. This calculates every map twice
+    window_metric: Option<f64>,
+
+    #[arg(long, default_value = "30.0", value_name = "SECONDS")]
+    /// The length of the opening when grouping by first-seconds, counted from the first note
+    first_seconds: f64,
+
+    #[arg(long)]
+    /// When interrupted with Ctrl-C, still write the collections for the maps processed so far
+    write_partial: bool,
+
+    #[arg(long)]
+    /// Number of threads calculating maps [default: number of CPU cores]
+    threads: Option<NonZeroUsize>,
+
+    #[arg(long, default_value = "5.0", value_name = "SECONDS")]
+    /// How often the number of processed maps is printed
+    progress_interval: f64,
+
+    #[arg(long)]
+    /// Number of .osu files read at the same time [default: same as --threads]
+    io_concurrency: Option<NonZeroUsize>,
+
+    #[arg(long)]
+    /// Print the maps added to and removed from each collection instead of every collection
+    report_only_changes: bool,
+
+    #[arg(long)]
+    /// Show how collection.db would change without writing it
+    dry_run: bool,
+
+    #[arg(long)]
+    /// Write collection.db even when no map ended up in a collection. Without it such a run stops
+    /// before writing, so an over-filtered run doesn't remove the previous collections
+    allow_empty: bool,
+
+    #[arg(long)]
+    /// Abort the run without writing anything at the first map that would be skipped because of a
+    /// problem with it: a missing or unparsable file, a file whose hash doesn't match osu!.db, a
+    /// file of another mode, a map without pp or a custom metric that can't be evaluated
+    strict: bool,
+
+    #[arg(long, value_name = "COMMAND")]
+    /// Run this command through the shell after collection.db was written successfully, with the
+    /// result in environment variables (see the readme)
+    on_complete: Option<String>,
+
+    #[arg(long, requires = "dry_run")]
+    /// Print the --dry-run changes as JSON, with the hashes of the added and removed maps. With
+    /// --output-format json they are only printed as part of the summary
+    dry_run_json: bool,
+
+    #[arg(long)]
+    /// Cache the calculated attributes in this file, so later runs only calculate new maps
+    cache: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "cache",
+        conflicts_with_all = ["scan_osz", "no_storyboard", "no_video"]
+    )]
+    /// Don't read anything in the Songs folder and only use the attributes in the --cache. Maps that
+    /// aren't in it are skipped
+    offline: bool,
+
+    #[arg(long, value_name = "PATH", requires = "cache")]
+    /// Add the calculations of another --cache file to the cache before calculating, eg. one copied
+    /// from another install. Maps are matched by their hash, so only maps that are the same
+    /// everywhere are taken from it
+    seed_cache: Option<PathBuf>,
+
+    #[arg(long)]
+    /// Only keep one difficulty per beatmapset in each collection
+    per_set: bool,
+
+    #[arg(long, value_enum, default_value = "highest")]
+    /// Which difficulty to keep with --per-set
+    per_set_pick: SetRepresentative,
+
+    #[arg(long, value_name = "MAPS", conflicts_with = "only_buckets")]
+    /// Merge buckets with fewer maps than this into a neighbouring bucket, the name then covers
+    /// the whole range (eg. "% 50%-70% Aim / 30%-50% Tapping")
+    merge_threshold: Option<usize>,
+
+    #[arg(long)]
+    /// Put the maps of each collection in a random order
+    shuffle: bool,
+
+    #[arg(long, requires = "shuffle")]
+    /// Seed for --shuffle, to get the same order again [default: random]
+    seed: Option<u64>,
+
+    #[arg(long)]
+    /// The maximum number of maps in all generated collections combined
+    max_maps_total: Option<usize>,
+
+    #[arg(long, value_enum, default_value = "proportional")]
+    /// How to pick the maps to drop when there are more than --max-maps-total
+    trim_strategy: TrimStrategy,
 }
 
-fn main() {
-    let args = Args::parse();
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CollectionOrder {
+    /// Before all other collections
+    Start,
+    /// After all other collections
+    End,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OutputFormat {
+    /// Progress and everything that happens, as text
+    Text,
+    /// Only a JSON summary of the run at the end, problems are printed to stderr
+    Json,
+    /// Only problems with maps or files, on stderr
+    Quiet,
+}
 
-    println!("Starting process with following arguments: {:?}", args);
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum NameCollision {
+    /// Leave out the generated collection
+    Skip,
+    /// Add a number to the name of the generated collection
+    Rename,
+    /// Add the generated collection anyway, creating two collections with the same name
+    Warn,
+}
 
-    let osu_path = Path::new(&args.osu_path);
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum TrimStrategy {
+    /// Shrink every collection by the same fraction
+    Proportional,
+    /// Keep the maps with the highest star rating, no matter which collection they are in
+    HardestFirst,
+    /// Take maps away from the largest collection until the limit is reached
+    LargestFirst,
+}
 
-    let db_path = osu_path.join("osu!.db");
-    if !db_path.exists() {
-        panic!("osu!.db was not found");
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SetRepresentative {
+    /// The difficulty with the highest star rating
+    Highest,
+    /// The difficulty with the lowest star rating
+    Lowest,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum RatioDenominator {
+    /// aim pp + speed pp, the rest of the ratio is tapping
+    AimSpeed,
+    /// total pp, including accuracy and flashlight pp
+    Total,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum GroupBy {
+    /// Aim/tapping ratio
+    Aim,
+    /// Average slider velocity of all sliders (maps without sliders are skipped)
+    Sv,
+    /// Percentile of the notes per second within a sliding window
+    Density,
+    /// Accuracy pp as a fraction of total pp
+    Acc,
+    /// Length bands like Short (<1:30) or Marathon (5:00+)
+    LengthBand,
+    /// Circle size, adjusted for HR and EZ
+    Cs,
+    /// Star rating band and aim/tapping ratio together, one collection for every combination
+    StarsAndRatio,
+    /// Star rating of only the first --first-seconds of the map
+    FirstSeconds,
+    /// Percent of the pp lost to a single miss, calculated a second time with one miss
+    MissSensitivity,
+    /// Star rating range across the difficulties of the beatmapset, all maps of a set end up together
+    DiffSpread,
+    /// Experimental: whether the difficulty ramps up, stays flat, drops off or comes in bursts
+    DensityCurveShape,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum LengthSource {
+    /// Drain time, without breaks and the time before the first note
+    Drain,
+    /// Total length of the map
+    Total,
+}
+
+/// Names of the length bands when there are as many as with the default boundaries.
+const LENGTH_BAND_NAMES: [&str; 4] = ["Short", "Medium", "Long", "Marathon"];
+
+/// The pp algorithm the maps are calculated with. rosu-pp only implements the current algorithm of
+/// its release, so this changes with the rosu-pp version and is recorded in the export and cache
+/// to tell results from different algorithms apart.
+const PP_VERSION: &str = "rosu-pp 0.10";
+
+/// The accuracy the pp is calculated for.
+const ACCURACY: f64 = 99.0;
+
+/// The part of the map that is calculated on its own besides the whole map.
+#[derive(Clone, Copy)]
+enum Window {
+    /// The densest part of this many seconds, for --window-metric.
+    Densest(f64),
+    /// The first this many seconds, when grouping by first-seconds.
+    Opening(f64),
+}
+
+impl Window {
+    fn new(args: &Args) -> Option<Self> {
+        let opening = args.metric_expr.is_none() && matches!(args.group_by, GroupBy::FirstSeconds);
+        match (args.window_metric, opening) {
+            (Some(_), true) => {
+                panic!("--window-metric can't be combined with --group-by first-seconds")
+            }
+            (Some(seconds), false) => Some(Self::Densest(seconds)),
+            (None, true) => Some(Self::Opening(args.first_seconds)),
+            (None, false) => None,
+        }
     }
 
-    let collection_path = osu_path.join("collection.db");
-    if !collection_path.exists() {
-        panic!("collection.db was not found");
+    fn part_of(self, map_pp: &rosu_pp::Beatmap) -> rosu_pp::Beatmap {
+        match self {
+            Self::Densest(seconds) => densest_window(map_pp, seconds),
+            Self::Opening(seconds) => opening(map_pp, seconds),
+        }
     }
+}
 
-    println!("Reading osu!.db");
-    let listing = Listing::from_file(&db_path).expect("Could not read osu!.db");
-    println!("Finished reading osu!.db");
+/// The score the pp is calculated for.
+#[derive(Clone, Copy)]
+struct Play {
+    accuracy: f64,
+    misses: usize,
+    /// The highest combo, `None` for a full combo apart from the misses.
+    combo: Option<usize>,
+}
 
-    let aim_ratio_groups = group_maps_by(&args, listing);
+impl Play {
+    fn new(args: &Args) -> Self {
+        Self {
+            accuracy: ACCURACY,
+            misses: args.misses,
+            combo: args.combo,
+        }
+    }
+
+    /// The same play with one more miss, for miss-sensitivity.
+    fn with_miss(self) -> Self {
+        Self {
+            misses: self.misses + 1,
+            ..self
+        }
+    }
+}
 
-    println!("Reading collection.db");
+/// Names of the curve shapes, by their bucket when grouping by density-curve-shape.
+const CURVE_SHAPE_NAMES: [&str; 4] = ["Ascending", "Flat", "Descending", "Bursty"];
 
-    let mut collections = CollectionList::from_file(&collection_path).unwrap();
+/// The number of equally long parts a difficulty curve is averaged into.
+const CURVE_SEGMENTS: usize = 16;
 
-    remove_previous_collections(&args, &mut collections);
-    add_new_collections(&args, aim_ratio_groups, &mut collections);
+/// Bucket of the maps without a known length when grouping by length-band.
+const UNKNOWN_LENGTH_BAND: i32 = -1;
 
-    collections.to_file(collection_path).unwrap();
+/// Set by the Ctrl-C handler, processing stops at the next map once this is set.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    println!("Successfully wrote collection.db");
+/// Identifies a single generated collection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct BucketKey {
+    mods: u32,
+    /// Only set when grouping by stars-and-ratio, `bucket` is the ratio then.
+    star_band: Option<i32>,
+    bucket: i32,
+    /// The last bucket that was merged into this one by --merge-threshold.
+    merged_through: Option<i32>,
 }
 
-fn group_maps_by(args: &Args, listing: Listing) -> HashMap<i32, Vec<Option<String>>> {
-    let filtered_maps: Vec<&Beatmap> = listing
-        .beatmaps
-        .iter()
-        .filter(|map| {
-            map.mode == Mode::Standard
-                && map
-                .std_ratings
-                .iter()
-                .find_map(|(mods, stars)| if mods.0 == 0 { Some(stars) } else { None })
-                .unwrap_or(&args.min_star_rating) // When star rating calcs haven't run yet, the star rating will not be set.
-                >= &args.min_star_rating
-        })
-        .collect();
+/// The groups a map was put in for each mod combination, and its metrics for --export-metrics.
+#[derive(Default)]
+struct ProcessedMap<'a> {
+    buckets: Vec<(BucketKey, GroupedMap<'a>)>,
+    metrics: Vec<MapMetrics>,
+    /// osu!.db lists the map as standard, but the `.osu` file is for another mode.
+    mode_mismatch: bool,
+    /// Skipped because of --max-break-fraction.
+    break_heavy: bool,
+    /// Skipped because it isn't in the cache with --offline.
+    not_cached: bool,
+}
 
-    println!(
-        "Found {} out of {} total maps to process",
-        filtered_maps.len(),
-        listing.beatmaps.len()
-    );
+/// A bucket with its maps, for passes that change the keys themselves.
+type Bucket<'a> = (BucketKey, Vec<GroupedMap<'a>>);
 
-    let now = Instant::now();
+/// The mods, the attributes of the whole map, of the [`Window`] and when grouping by
+/// miss-sensitivity of a play with one miss.
+type MapAttributes = (
+    u32,
+    OsuPerformanceAttributes,
+    Option<OsuPerformanceAttributes>,
+    Option<OsuPerformanceAttributes>,
+);
 
-    let mut count = 0;
+/// Everything known about a map after calculating it with one mod combination.
+struct MapCalculation<'a> {
+    map: &'a Beatmap,
+    path: &'a Path,
+    /// Not read when the attributes came from the cache and the grouping doesn't need the map.
+    parsed: Option<&'a rosu_pp::Beatmap>,
+    mods: u32,
+    pp: OsuPerformanceAttributes,
+    /// The attributes of only the [`Window`] of the map.
+    window_pp: Option<OsuPerformanceAttributes>,
+    /// The attributes of a play with one miss, when grouping by miss-sensitivity.
+    miss_pp: Option<OsuPerformanceAttributes>,
+    /// The [`difficulty_curve`] when grouping by density-curve-shape.
+    curve: Option<Vec<f64>>,
+}
 
-    filtered_maps
-        .iter()
-        .fold(HashMap::new(), |mut hash_map, map| {
-            let map_path = Path::new(&args.osu_path)
-                .join("Songs")
-                .join(map.folder_name.as_ref().unwrap())
-                .join(map.file_name.as_ref().unwrap());
-
-            let map_pp = match rosu_pp::Beatmap::from_path(&map_path) {
-                Ok(map) => map,
-                Err(why) => {
-                    println!(
-                        "Error while parsing {}: {}",
-                        map_path.to_str().unwrap_or_default(),
-                        why
-                    );
-                    return hash_map;
-                }
-            };
+/// Where the `.osu` files are read from. Maps from `--scan-osz` are already in memory, all others
+/// are read from disk.
+struct MapFiles<'a> {
+    io_limit: IoLimit,
+    archived: Option<&'a HashMap<String, Vec<u8>>>,
+}
 
-            if let PerformanceAttributes::Osu(pp) = map_pp.pp().accuracy(99f64).calculate() {
-                let aim_aspect = pp.pp_aim / (pp.pp_aim + pp.pp_speed);
-                let rounded_aim_aspect = ((aim_aspect * 100f64 / args.ratio_precision).floor()
-                    * args.ratio_precision) as i32;
+/// A map that passed all filters, together with the values calculated for it.
+struct GroupedMap<'a> {
+    map: &'a Beatmap,
+    stars: f64,
+}
 
-                Vec::push(
-                    hash_map
-                        .entry(rounded_aim_aspect)
-                        .or_default(),
-                    map.hash.clone(),
-                );
-            }
+/// Paths don't have to be valid UTF-8, but the export should still contain something readable.
+fn serialize_path_lossy<S: serde::Serializer>(
+    path: &Path,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
 
-            count += 1;
+fn serialize_regex<S: serde::Serializer>(
+    regex: &Option<Regex>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    regex.as_ref().map(Regex::as_str).serialize(serializer)
+}
 
-            if count % 100 == 0 {
-                println!(
-                    "Processed {}/{} maps in {:.1} seconds",
-                    count,
-                    filtered_maps.len(),
-                    now.elapsed().as_secs_f32()
-                );
-            }
+/// Variables that can be used in `--metric-expr`.
+const METRIC_VARIABLES: [&str; 10] = [
+    "aim", "speed", "fl", "stars", "bpm", "length", "ar", "od", "cs", "combo",
+];
 
-            hash_map
-        })
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two files created with --export-collector and report which maps moved between collections
+    Compare {
+        /// The export of the earlier run
+        old: PathBuf,
+
+        /// The export of the later run
+        new: PathBuf,
+
+        #[arg(long)]
+        /// Print the differences as JSON instead
+        json: bool,
+    },
+    /// Print the star rating and pp breakdown of a single .osu file, in any mode
+    Inspect {
+        /// The .osu file to inspect
+        map: PathBuf,
+
+        #[arg(long = "mods", default_value = "NM")]
+        /// The mod combination to calculate with, eg. HDDT
+        mod_combination: String,
+
+        #[arg(long, default_value = "99")]
+        /// The accuracy to calculate the pp for
+        accuracy: f64,
+
+        #[arg(long, value_enum)]
+        /// Calculate an osu!standard map as a convert to this mode
+        convert: Option<ConvertMode>,
+    },
+    /// Write collections from a JSON or CSV file of map hashes into collection.db, without
+    /// calculating anything
+    Import {
+        /// A JSON object of collection names to hashes, an --export-collector file, or a CSV file
+        /// with one `collection,hash` line per map
+        file: PathBuf,
+
+        #[arg(value_name = "OSU_PATH", default_value = ".")]
+        /// Path to the osu! directory
+        osu_dir: PathBuf,
+
+        #[arg(long = "collection-prefix", default_value = "% ")]
+        /// The prefix of the collections to replace, added to imported names that don't have it
+        prefix: String,
+
+        #[arg(long = "no-clean")]
+        /// Keep the collections with the prefix instead of removing them first
+        keep_previous: bool,
+
+        #[arg(long = "ignore-running")]
+        /// Don't warn when osu! is running, which overwrites collection.db when it is closed
+        osu_may_run: bool,
+    },
+    /// Look into or delete a --cache file
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
 }
 
-fn add_new_collections(
-    args: &Args,
-    aim_ratio_groups: HashMap<i32, Vec<Option<String>>>,
-    collections: &mut CollectionList,
-) {
-    for (aim_ratio, maps) in aim_ratio_groups {
-        let prefix = &args.collection_prefix;
-        let collection_name = format!("{prefix}{aim_ratio}% Aim / {}% Tapping", 100 - aim_ratio);
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Print the size, version and number of entries of the cache, how many of them belong to maps
+    /// that are still in osu!.db, and how the cached aim ratios are distributed
+    Stats {
+        /// The cache file
+        file: PathBuf,
+
+        #[arg(value_name = "OSU_PATH", default_value = ".")]
+        /// Path to the osu! directory
+        osu_dir: PathBuf,
+    },
+    /// Delete the cache, the next run with --cache calculates every map again
+    Clear {
+        /// The cache file
+        file: PathBuf,
+    },
+}
 
-        println!("Adding {collection_name} with {} maps", maps.len());
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ConvertMode {
+    Taiko,
+    Mania,
+    Catch,
+}
 
-        collections.collections.push(Collection {
-            name: Some(collection_name),
-            beatmap_hashes: maps,
-        });
+impl ConvertMode {
+    fn game_mode(self) -> GameMode {
+        match self {
+            ConvertMode::Taiko => GameMode::Taiko,
+            ConvertMode::Mania => GameMode::Mania,
+            ConvertMode::Catch => GameMode::Catch,
+        }
+    }
+
+    /// The same mode as osu!.db stores it.
+    fn listed_mode(self) -> Mode {
+        match self {
+            ConvertMode::Taiko => Mode::Taiko,
+            ConvertMode::Mania => Mode::Mania,
+            ConvertMode::Catch => Mode::CatchTheBeat,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ConvertMode::Taiko => "Taiko",
+            ConvertMode::Mania => "Mania",
+            ConvertMode::Catch => "Catch",
+        }
     }
 }
 
-fn remove_previous_collections(args: &Args, collections: &mut CollectionList) {
-    let collection_count = collections.collections.len();
-    collections.collections.retain(|c| {
-        if let Some(name) = &c.name {
-            !name.starts_with(&args.collection_prefix)
-        } else {
-            true
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Normalize {
+    /// 50% + (aim stars - speed stars) / (2 * star rating)
+    Stars,
+    /// 50% + (aim pp - speed pp) / (2 * total pp)
+    Pp,
+}
+
+fn main() {
+    let mut args = Args::parse();
+
+    match &args.command {
+        Some(Command::Compare { old, new, json }) => return compare::run(old, new, *json),
+        Some(Command::Inspect {
+            map,
+            mod_combination,
+            accuracy,
+            convert,
+        }) => return inspect::run(map, mod_combination, *accuracy, *convert),
+        Some(Command::Import {
+            file,
+            osu_dir,
+            prefix,
+            keep_previous,
+            osu_may_run,
+        }) => {
+            if !osu_may_run {
+                warn_osu_running();
+            }
+            return import::run(
+                file,
+                osu_dir,
+                &normalize_prefix(prefix, false),
+                *keep_previous,
+            );
+        }
+        Some(Command::Cache { action }) => {
+            return match action {
+                CacheCommand::Stats { file, osu_dir } => cache::stats(file, osu_dir),
+                CacheCommand::Clear { file } => cache::clear(file),
+            }
         }
+        None => {}
+    }
+
+    output::set_quiet(!matches!(args.output_format, OutputFormat::Text));
+    output::set_strict(args.strict);
+    args.collection_prefix = normalize_prefix(&args.collection_prefix, args.allow_empty_prefix);
+    status!("Starting process with following arguments: {:?}", args);
+
+    let metric_expr = args.metric_expr.as_deref().map(parse_metric_expr);
+    if args.offline {
+        check_offline(&args, metric_expr.as_ref());
+    }
+    if let Some(mode) = args.mode {
+        check_mode(&args, mode);
+    }
+    warn_uneven_ratio_precision(&args);
+    // The window is picked again for every map in the worker threads, check the options once here
+    // before osu!.db is read.
+    let _ = Window::new(&args);
+
+    let only_buckets = selected_buckets(&args);
+
+    if !args.length_bands.windows(2).all(|pair| pair[0] < pair[1]) {
+        panic!("--length-bands must be in ascending order");
+    }
+
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))
+        .expect("Could not set Ctrl-C handler");
+    let mod_combinations = mods::combinations(&args.mods);
+
+    if mod_combinations.len() > 1 {
+        status!(
+            "Calculating {} mod combinations, this takes about {} times as long and creates a set of collections for each",
+            mod_combinations.len(),
+            mod_combinations.len()
+        );
+    }
+
+    let osu_path = args.osu_path.as_path();
+
+    let collection_path = osu_path.join("collection.db");
+    if !collection_path.exists() {
+        panic!("collection.db was not found");
+    }
+
+    if !args.ignore_running && !args.dry_run {
+        warn_osu_running();
+    }
+
+    // Parsing osu!.db can't be split up, the records have no fixed size, but a large cache takes
+    // about as long to parse and doesn't depend on it.
+    let (listing, archived, cache) = std::thread::scope(|scope| {
+        let cache = scope.spawn(|| {
+            let cache = args.cache.as_deref().map(Cache::load);
+            if let (Some(cache), Some(seed)) = (&cache, &args.seed_cache) {
+                cache.seed(seed);
+            }
+            cache
+        });
+        let (listing, archived) = match &args.scan_osz {
+            Some(dir) => {
+                status!("Reading .osz archives in {}", dir.display());
+                let archived = osz::scan(dir);
+                (archived.listing, Some(archived.files))
+            }
+            None => (read_listing(osu_path), None),
+        };
+        let cache = cache
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (listing, archived, cache)
     });
 
-    println!(
-        "Removed {} collections from previous iteration",
-        collection_count - collections.collections.len()
+    if !args.no_ratings_check {
+        check_ratings_computed(&args, &listing);
+    }
+
+    let listing_version = listing.version;
+    let total_maps = listing.beatmaps.len();
+    let (mut aim_ratio_groups, leftovers) = group_maps_by(
+        &args,
+        metric_expr.as_ref(),
+        &mod_combinations,
+        &listing,
+        archived.as_ref(),
+        cache,
     );
+
+    if INTERRUPTED.load(Ordering::Relaxed) && !args.write_partial {
+        problem!(
+            "Exiting without writing collection.db, pass --write-partial to keep partial results"
+        );
+        std::process::exit(130);
+    }
+
+    if let Some(only_buckets) = &only_buckets {
+        aim_ratio_groups.retain(|key, _| only_buckets.contains(&key.bucket));
+    }
+
+    if args.per_set {
+        collapse_beatmapsets(&args, &mut aim_ratio_groups);
+    }
+    if let Some(threshold) = args.merge_threshold {
+        merge_small_buckets(&args, threshold, &mut aim_ratio_groups);
+    }
+    if let Some(labels) = &args.labels {
+        apply_labels(&args, labels, &mut aim_ratio_groups);
+    }
+    if let Some(max_maps) = args.max_maps_total {
+        trim_to_total(&args, max_maps, &mut aim_ratio_groups);
+    }
+    if args.shuffle {
+        shuffle_groups(&args, &mut aim_ratio_groups);
+    }
+
+    report_bucket_balance(&args, &aim_ratio_groups);
+    warn_star_band_growth(&args, &aim_ratio_groups);
+
+    let grouped_maps = aim_ratio_groups
+        .values()
+        .flatten()
+        .map(|grouped| grouped.map.hash.as_deref())
+        .collect::<HashSet<_>>()
+        .len();
+
+    if grouped_maps == 0 && leftovers.is_empty() {
+        problem!("Warning: no map ended up in a collection");
+        report_empty_cause(&args, &listing, &mod_combinations);
+        if !args.allow_empty && !args.dry_run {
+            problem!("Exiting without writing collection.db so the previous collections are kept, pass --allow-empty to write it anyway");
+            std::process::exit(1);
+        }
+    }
+
+    status!("Reading collection.db");
+
+    let mut collections = CollectionList::from_file(&collection_path).unwrap();
+
+    upgrade_collection_version(listing_version, &mut collections);
+    if !args.no_clean {
+        check_clean_regex(&args, &collections);
+    }
+    let manual = manual_collections(&args, &collections);
+    let previous = shows_changes(&args).then(|| changes::managed_collections(&args, &collections));
+    // With --merge the collections that are created again are merged into where they are instead
+    // of being removed and added back.
+    let merged_into: HashSet<String> = match args.merge {
+        true => aim_ratio_groups
+            .iter()
+            .map(|(&key, maps)| collection_name(&args, key, maps))
+            .collect(),
+        false => HashSet::new(),
+    };
+    let removed_collections = match args.no_clean {
+        true => 0,
+        false => remove_previous_collections(
+            &args,
+            only_buckets.as_ref(),
+            &mod_combinations,
+            &merged_into,
+            &mut collections,
+        ),
+    };
+    let mut created = add_new_collections(&args, &aim_ratio_groups, &mut collections);
+    if let Some(name) = leftovers_name(&args) {
+        created.push(add_leftovers(&args, name, &leftovers, &mut collections));
+    }
+
+    if manual != manual_collections(&args, &collections) {
+        panic!("Collections without the prefix were changed, refusing to write collection.db");
+    }
+
+    // Exported with the names that end up in collection.db, after collisions were resolved.
+    if let Some(export_path) = &args.export_collector {
+        export::write_collector(export_path, &args, &aim_ratio_groups, &created, &leftovers);
+    }
+
+    let changes = previous.map(|previous| {
+        changes::diff(
+            &previous,
+            &changes::managed_collections(&args, &collections),
+        )
+    });
+    if let Some(changes) = &changes {
+        // The JSON summary already has the changes, a second document would make stdout invalid.
+        match (args.dry_run_json, args.output_format) {
+            (true, OutputFormat::Text) => {
+                println!("{}", serde_json::to_string_pretty(changes).unwrap())
+            }
+            (false, OutputFormat::Text) => changes::print(changes, &listing),
+            (_, _) => {}
+        }
+    }
+
+    let summary = Summary {
+        total_maps,
+        grouped_maps,
+        removed_collections,
+        collections: created,
+        changes,
+        dry_run: args.dry_run,
+    };
+
+    if args.dry_run {
+        status!("Dry run, collection.db was not changed");
+        print_summary(&args, &summary);
+        return;
+    }
+
+    // osu_db serializes straight into a buffered file writer without copying the collections, so
+    // the only thing left to do is to free the listing, which is by far the largest allocation.
+    drop(listing);
+    let written = write_temporary(&collection_path, &collections);
+
+    if !args.no_verify_write {
+        verify_written(&args, &written, &collections);
+    }
+    replace_collection_db(&collection_path, &written);
+
+    status!("Successfully wrote collection.db");
+    print_summary(&args, &summary);
+
+    if let Some(command) = &args.on_complete {
+        run_on_complete(command, &collection_path, &summary);
+    }
+}
+
+/// Runs the --on-complete command and reports how it exited. It only runs after a successful write,
+/// so a failing command doesn't change anything about the run.
+fn run_on_complete(command: &str, collection_path: &Path, summary: &Summary) {
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let mut shell = std::process::Command::new(shell);
+    shell.arg(flag).arg(command);
+    // The summary on stdout has to stay readable for the JSON output.
+    if output::quiet() {
+        shell.stdout(std::io::stderr());
+    }
+
+    let created: usize = summary.collections.iter().map(|c| c.maps).sum();
+    shell
+        .env("ARC_COLLECTION_DB", collection_path)
+        .env(
+            "ARC_BACKUP",
+            collection_path.with_file_name("collection.db.bak"),
+        )
+        .env("ARC_OSU_DIR", collection_path.parent().unwrap())
+        .env("ARC_COLLECTIONS", summary.collections.len().to_string())
+        .env("ARC_COLLECTION_MAPS", created.to_string())
+        .env("ARC_GROUPED_MAPS", summary.grouped_maps.to_string())
+        .env("ARC_TOTAL_MAPS", summary.total_maps.to_string())
+        .env(
+            "ARC_REMOVED_COLLECTIONS",
+            summary.removed_collections.to_string(),
+        );
+
+    status!("Running --on-complete command: {command}");
+    match shell.status() {
+        Ok(status) if status.success() => status!("--on-complete command finished successfully"),
+        Ok(status) => problem!("--on-complete command failed with {status}"),
+        Err(why) => problem!("Could not run --on-complete command: {why}"),
+    }
+}
+
+/// The cache only has the performance attributes, everything that needs the map itself can't be
+/// done without reading the Songs folder.
+fn check_offline(args: &Args, metric_expr: Option<&Node>) {
+    if needs_parsed_map(args, metric_expr) {
+        panic!("--offline only has the cached attributes, but grouping by sv, density or a custom metric and --max-break-fraction need the .osu files");
+    }
+    let seeded = args.seed_cache.is_some();
+    if let Some(path) = args.cache.as_ref().filter(|path| !path.exists() && !seeded) {
+        panic!(
+            "--offline needs an existing cache or --seed-cache, but {} doesn't exist yet",
+            path.display()
+        );
+    }
+}
+
+/// The other modes have no aim and speed pp, so only the groupings that don't need them can be
+/// used. Mania has no sliders and taiko doesn't use the circle size, which is the keycount in mania.
+fn check_mode(args: &Args, mode: ConvertMode) {
+    let supported: &[GroupBy] = match mode {
+        ConvertMode::Taiko => &[GroupBy::LengthBand, GroupBy::Density, GroupBy::Sv],
+        ConvertMode::Mania => &[GroupBy::LengthBand, GroupBy::Density, GroupBy::Cs],
+        ConvertMode::Catch => &[
+            GroupBy::LengthBand,
+            GroupBy::Density,
+            GroupBy::Sv,
+            GroupBy::Cs,
+        ],
+    };
+    if args.single_collection.is_none() && !supported.contains(&args.group_by) {
+        let names: Vec<String> = supported
+            .iter()
+            .map(|group_by| group_by.to_possible_value().unwrap().get_name().to_string())
+            .collect();
+        panic!(
+            "--mode {} only groups by {} or into a --single-collection",
+            mode.name().to_lowercase(),
+            names.join(", ")
+        );
+    }
+}
+
+fn warn_osu_running() {
+    if running::osu_running() {
+        problem!("Warning: osu! is running and writes its own collections to collection.db when it is closed, overwriting the new ones. Close osu! before running this, or pass --ignore-running");
+    }
+}
+
+/// Names the filter that most likely left no maps. The osu!.db filters are tried one at a time on
+/// the maps of the grouped mode, anything that passes them was dropped later while calculating.
+fn report_empty_cause(args: &Args, listing: &Listing, mod_combinations: &[u32]) {
+    let standard: Vec<&Beatmap> = listing
+        .beatmaps
+        .iter()
+        .filter(|map| is_grouped_mode(args, map))
+        .collect();
+    if standard.is_empty() {
+        problem!("osu!.db doesn't contain any {} maps", mode_label(args));
+        return;
+    }
+
+    let filters = [
+        (
+            "--min-star-rating",
+            standard.iter().any(|map| {
+                mod_combinations.iter().any(|&mods| {
+                    listed_mode_stars(args, map, mods)
+                        .is_none_or(|stars| stars >= args.min_star_rating)
+                })
+            }),
+        ),
+        (
+            "the length limits (--min-drain, --max-drain, --min-length, --max-length)",
+            standard.iter().any(|map| within_length_limits(args, map)),
+        ),
+        (
+            "the version filters (--include-version-contains, --exclude-version-contains)",
+            standard
+                .iter()
+                .any(|map| matches_version_filters(args, map)),
+        ),
+    ];
+
+    let mut found = false;
+    for (name, any_passes) in filters {
+        if !any_passes {
+            problem!(
+                "None of the {} {} maps passes {name}",
+                standard.len(),
+                mode_label(args)
+            );
+            found = true;
+        }
+    }
+
+    if !found {
+        match args.only_buckets.is_empty() {
+            false => problem!("No map fell into the buckets of --only-buckets"),
+            true => problem!("The maps were skipped after the osu!.db filters, check --no-storyboard, --no-video, --min-pp, --max-break-fraction and the skipped counts above"),
+        }
+    }
+}
+
+fn print_summary(args: &Args, summary: &Summary) {
+    if let OutputFormat::Json = args.output_format {
+        println!("{}", serde_json::to_string_pretty(summary).unwrap());
+    }
+}
+
+/// Writes the collections next to collection.db instead of over it, so a crash or full disk in the
+/// middle of writing leaves the old file intact. Returns the path of the written file.
+fn write_temporary(collection_path: &Path, collections: &CollectionList) -> PathBuf {
+    let temporary = collection_path.with_file_name("collection.db.tmp");
+    File::create(&temporary)
+        .and_then(|file| write_synced(file, collections))
+        .unwrap_or_else(|why| {
+            let _ = std::fs::remove_file(&temporary);
+            panic!("Could not write {}: {why}", temporary.display())
+        });
+    temporary
+}
+
+/// Writes through a buffer and syncs the same handle it was written with. Opening the file again
+/// only for reading wouldn't work on Windows, where syncing needs write access, and dropping the
+/// buffer would silently lose an error while flushing it.
+fn write_synced(file: File, collections: &CollectionList) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    collections.to_writer(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|why| why.into_error())?
+        .sync_all()
+}
+
+/// Keeps the previous collection.db as collection.db.bak and moves the new file in its place. The
+/// rename replaces the file in one step, so there is never a half written collection.db.
+fn replace_collection_db(collection_path: &Path, written: &Path) {
+    let backup = collection_path.with_file_name("collection.db.bak");
+    std::fs::copy(collection_path, &backup)
+        .unwrap_or_else(|why| panic!("Could not back up collection.db: {why}"));
+    std::fs::rename(written, collection_path)
+        .unwrap_or_else(|why| panic!("Could not replace collection.db: {why}"));
+}
+
+/// Reads the written file back and checks that every generated collection made it into it with
+/// all of its maps, so a broken write shows up here instead of in game. This happens before the
+/// file replaces collection.db, which stays untouched when the check fails.
+fn verify_written(args: &Args, path: &Path, collections: &CollectionList) {
+    let reloaded = CollectionList::from_file(path).unwrap_or_else(|why| {
+        panic!(
+            "{} could not be read back after writing, collection.db was not changed: {why}",
+            path.display()
+        )
+    });
+
+    let expected = changes::managed_collections(args, collections);
+    let written = changes::managed_collections(args, &reloaded);
+    let mismatched: Vec<&String> = expected
+        .iter()
+        .filter(|(name, hashes)| written.get(*name).map(Vec::len) != Some(hashes.len()))
+        .map(|(name, _)| name)
+        .collect();
+
+    if !mismatched.is_empty() || reloaded.collections.len() != collections.collections.len() {
+        for name in &mismatched {
+            problem!(
+                "{name} should have {} maps, but has {} in the written file",
+                expected[*name].len(),
+                written.get(*name).map_or(0, Vec::len)
+            );
+        }
+        panic!(
+            "{} doesn't contain what was written, collection.db was not changed",
+            path.display()
+        );
+    }
+}
+
+fn read_listing(osu_path: &Path) -> Listing {
+    let db_path = osu_path.join("osu!.db");
+    if !db_path.exists() {
+        panic!("osu!.db was not found");
+    }
+
+    status!("Reading osu!.db");
+    let listing = Listing::from_file(&db_path).expect("Could not read osu!.db");
+    status!("Finished reading osu!.db");
+
+    // Older osu!.db versions or fresh installs might not have a player name set.
+    match listing
+        .player_name
+        .as_deref()
+        .filter(|name| !name.is_empty())
+    {
+        Some(player_name) => status!("Building collections for {player_name}"),
+        None => problem!(
+            "osu!.db does not contain a player name, make sure {} is the right osu! directory",
+            osu_path.display()
+        ),
+    }
+
+    listing
+}
+
+/// osu! calculates the star ratings in the background after importing maps. Until it is done the
+/// maps have no rating, so --min-star-rating lets all of them through and the collections are
+/// full of maps that should have been filtered out.
+fn check_ratings_computed(args: &Args, listing: &Listing) {
+    let standard_maps = listing
+        .beatmaps
+        .iter()
+        .filter(|map| is_grouped_mode(args, map));
+    let total = standard_maps.clone().count();
+    let rated = standard_maps
+        .filter(|map| mode_ratings(args, map).iter().any(|(mods, _)| mods.0 == 0))
+        .count();
+
+    if total > 0 && (rated as f64) < args.min_ratings_computed * total as f64 {
+        problem!(
+            "Warning: only {rated} of {total} {} maps have a star rating, open osu! and let it finish calculating star ratings first (pass --no-ratings-check to hide this)",
+            mode_label(args)
+        );
+    }
+}
+
+/// Whether the map is of the mode that is grouped. Standard maps also count for another --mode
+/// with --include-converts.
+fn is_grouped_mode(args: &Args, map: &Beatmap) -> bool {
+    match args.mode {
+        Some(mode) => {
+            map.mode == mode.listed_mode() || (args.include_converts && map.mode == Mode::Standard)
+        }
+        None => map.mode == Mode::Standard,
+    }
+}
+
+/// The mode of the grouped maps in messages, eg. "taiko".
+fn mode_label(args: &Args) -> String {
+    args.mode
+        .map_or("standard".to_string(), |mode| mode.name().to_lowercase())
+}
+
+fn group_maps_by<'a>(
+    args: &Args,
+    metric_expr: Option<&Node>,
+    mod_combinations: &[u32],
+    listing: &'a Listing,
+    archived: Option<&HashMap<String, Vec<u8>>>,
+    cache: Option<Cache>,
+) -> (BTreeMap<BucketKey, Vec<GroupedMap<'a>>>, Vec<&'a Beatmap>) {
+    // When star rating calcs haven't run yet, the star rating will not be set.
+    let passes_stars = |map: &Beatmap| {
+        mod_combinations.iter().any(|&mods| {
+            listed_mode_stars(args, map, mods).is_none_or(|stars| stars >= args.min_star_rating)
+        })
+    };
+    // For --leftovers the star rating is checked last, so the maps below it passed every other
+    // filter.
+    let filtered_maps: Vec<&Beatmap> = listing
+        .beatmaps
+        .iter()
+        .filter(|map| {
+            is_grouped_mode(args, map)
+                && (args.leftovers.is_some() || passes_stars(map))
+                && within_length_limits(args, map)
+                && matches_version_filters(args, map)
+        })
+        .collect();
+    let filtered_maps = filter_media(args, filtered_maps);
+    let filtered_maps = remove_duplicate_hashes(args, filtered_maps);
+    let (filtered_maps, leftovers): (Vec<&Beatmap>, Vec<&Beatmap>) =
+        filtered_maps.into_iter().partition(|map| passes_stars(map));
+    if args.leftovers.is_some() {
+        status!(
+            "Found {} maps below {} stars for the leftovers",
+            leftovers.len(),
+            args.min_star_rating
+        );
+    }
+
+    status!(
+        "Found {} out of {} total maps to process",
+        filtered_maps.len(),
+        listing.beatmaps.len()
+    );
+
+    let threads = args.threads.map_or_else(
+        || std::thread::available_parallelism().map_or(1, usize::from),
+        usize::from,
+    );
+    let files = MapFiles {
+        io_limit: IoLimit::new(args.io_concurrency.map_or(threads, usize::from)),
+        archived,
+    };
+
+    let now = Instant::now();
+    let next = AtomicUsize::new(0);
+    let count = AtomicUsize::new(0);
+    // Milliseconds since `now` of the last progress message.
+    let last_progress = AtomicU64::new(0);
+    let progress_interval = (args.progress_interval * 1000f64) as u64;
+    let below_min_pp = AtomicUsize::new(0);
+    let mode_mismatches = AtomicUsize::new(0);
+    let break_heavy = AtomicUsize::new(0);
+    let not_cached = AtomicUsize::new(0);
+
+    let (results, metrics): (Vec<_>, Vec<_>) = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results: Vec<(usize, BucketKey, GroupedMap)> = Vec::new();
+                    let mut metrics: Vec<(usize, MapMetrics)> = Vec::new();
+
+                    while !INTERRUPTED.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&map) = filtered_maps.get(index) else {
+                            break;
+                        };
+
+                        let processed = process_map(
+                            args,
+                            metric_expr,
+                            mod_combinations,
+                            &files,
+                            cache.as_ref(),
+                            &below_min_pp,
+                            map,
+                        );
+                        if processed.mode_mismatch {
+                            mode_mismatches.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if processed.break_heavy {
+                            break_heavy.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if processed.not_cached {
+                            not_cached.fetch_add(1, Ordering::Relaxed);
+                        }
+                        for (key, grouped) in processed.buckets {
+                            results.push((index, key, grouped));
+                        }
+                        metrics.extend(processed.metrics.into_iter().map(|m| (index, m)));
+
+                        let processed = count.fetch_add(1, Ordering::Relaxed) + 1;
+                        let elapsed = now.elapsed().as_millis() as u64;
+                        let last = last_progress.load(Ordering::Relaxed);
+                        // Only the thread that moves the time forward prints, so a message isn't
+                        // repeated by every thread finishing a map at the same moment.
+                        if elapsed >= last + progress_interval
+                            && last_progress
+                                .compare_exchange(
+                                    last,
+                                    elapsed,
+                                    Ordering::Relaxed,
+                                    Ordering::Relaxed,
+                                )
+                                .is_ok()
+                        {
+                            status!(
+                                "Processed {}/{} maps in {:.1} seconds",
+                                processed,
+                                filtered_maps.len(),
+                                now.elapsed().as_secs_f32()
+                            );
+                        }
+                    }
+
+                    (results, metrics)
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .unzip()
+    });
+    let mut results: Vec<_> = results.into_iter().flatten().collect();
+    let mut metrics: Vec<_> = metrics.into_iter().flatten().collect();
+
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        problem!(
+            "Interrupted after processing {} maps",
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    // Saved even when interrupted, so the next run doesn't have to calculate these maps again.
+    if let Some(cache) = cache {
+        cache.save();
+    }
+
+    if let Some(min_pp) = args.min_pp {
+        // Every mod combination is checked on its own, so a map can be skipped for some only.
+        let unit = match mod_combinations.len() {
+            1 => "maps",
+            _ => "map and mod combinations",
+        };
+        status!(
+            "Skipped {} {unit} below {min_pp}pp",
+            below_min_pp.load(Ordering::Relaxed)
+        );
+    }
+
+    if let Some(max) = args.max_break_fraction {
+        status!(
+            "Skipped {} maps where breaks take up more than {:.0}% of the map",
+            break_heavy.load(Ordering::Relaxed),
+            max * 100f64
+        );
+    }
+
+    let not_cached = not_cached.load(Ordering::Relaxed);
+    if not_cached > 0 {
+        problem!("Skipped {not_cached} maps that aren't in the cache, run once without --offline to calculate them");
+    }
+
+    let mode_mismatches = mode_mismatches.load(Ordering::Relaxed);
+    if mode_mismatches > 0 {
+        problem!("Skipped {mode_mismatches} maps that aren't standard maps although osu!.db says they are, refresh osu!.db by opening osu! or pressing F5 in song select");
+    }
+
+    // Keep the maps in listing order no matter which thread finished first.
+    results.sort_by_key(|(index, ..)| *index);
+
+    if let Some(path) = &args.export_metrics {
+        metrics.sort_by_key(|(index, _)| *index);
+        let metrics: Vec<MapMetrics> = metrics.into_iter().map(|(_, m)| m).collect();
+        export::write_metrics(path, &metrics);
+    }
+
+    let spreads = groups_by_set(args).then(|| star_spreads(listing));
+    let mut groups: BTreeMap<BucketKey, Vec<GroupedMap>> = BTreeMap::new();
+    for (_, mut key, grouped) in results {
+        if let Some(spreads) = &spreads {
+            let spread = spreads
+                .get(&beatmapset(grouped.map))
+                .copied()
+                .unwrap_or_default();
+            key.bucket = (spread / args.spread_precision).floor() as i32;
+            if args.explain {
+                detail!(
+                    "{} {}: set spread {spread:.2}★ -> {}",
+                    grouped.map.file_name.as_deref().unwrap_or_default(),
+                    mods::name(key.mods),
+                    bucket_name(args, key)
+                );
+            }
+        }
+        groups.entry(key).or_default().push(grouped);
+    }
+
+    (groups, leftovers)
+}
+
+fn groups_by_set(args: &Args) -> bool {
+    args.single_collection.is_none()
+        && args.metric_expr.is_none()
+        && matches!(args.group_by, GroupBy::DiffSpread)
+}
+
+/// Maps are in the same set when they share a beatmapset id. Unsubmitted maps all have none, so
+/// they are told apart by their folder instead.
+fn beatmapset(map: &Beatmap) -> (i32, Option<&str>) {
+    match map.beatmapset_id > 0 {
+        true => (map.beatmapset_id, None),
+        false => (0, map.folder_name.as_deref()),
+    }
+}
+
+/// The difference between the hardest and the easiest standard difficulty of every set, from the
+/// nomod star ratings in osu!.db. This looks at every difficulty, also the ones that were filtered
+/// out, and difficulties osu! hasn't rated yet don't count.
+fn star_spreads(listing: &Listing) -> HashMap<(i32, Option<&str>), f64> {
+    let mut ranges: HashMap<(i32, Option<&str>), (f64, f64)> = HashMap::new();
+    for map in &listing.beatmaps {
+        if map.mode != Mode::Standard {
+            continue;
+        }
+        let Some(stars) = listed_stars(map, 0) else {
+            continue;
+        };
+        let range = ranges
+            .entry(beatmapset(map))
+            .or_insert((f64::INFINITY, f64::NEG_INFINITY));
+        *range = (range.0.min(stars), range.1.max(stars));
+    }
+
+    ranges
+        .into_iter()
+        .map(|(set, (min, max))| (set, max - min))
+        .collect()
+}
+
+/// Reads and calculates a single map, returning the bucket it falls into for every mod combination.
+fn process_map<'a>(
+    args: &Args,
+    metric_expr: Option<&Node>,
+    mod_combinations: &[u32],
+    files: &MapFiles,
+    cache: Option<&Cache>,
+    below_min_pp: &AtomicUsize,
+    map: &'a Beatmap,
+) -> ProcessedMap<'a> {
+    let map_path = override_path(args, map).unwrap_or_else(|| {
+        args.osu_path
+            .join("Songs")
+            .join(map.folder_name.as_ref().unwrap())
+            .join(map.file_name.as_ref().unwrap())
+    });
+
+    // The cache is keyed by the hash of the original map, which doesn't match an edited one.
+    let edited = override_path(args, map).is_some();
+    let cache = cache.filter(|_| !edited);
+
+    let play = Play::new(args);
+    let cached: Option<Vec<MapAttributes>> = cache.and_then(|cache| {
+        mod_combinations
+            .iter()
+            .map(|&mods| {
+                let window_pp = match Window::new(args) {
+                    Some(window) => Some(cache.get(map, mods, play, Some(window))?),
+                    None => None,
+                };
+                let miss_pp = match needs_miss_pp(args) {
+                    true => Some(cache.get(map, mods, play.with_miss(), None)?),
+                    false => None,
+                };
+                let pp = cache.get(map, mods, play, None)?;
+                Some((mods, pp, window_pp, miss_pp))
+            })
+            .collect()
+    });
+
+    // Curves only depend on the map and mods, so they are cached apart from the attributes.
+    let cached_curves: Option<HashMap<u32, Vec<f64>>> = match needs_curve(args) {
+        true => cache.and_then(|cache| {
+            mod_combinations
+                .iter()
+                .map(|&mods| Some((mods, cache.curve(map, mods)?)))
+                .collect()
+        }),
+        false => Some(HashMap::new()),
+    };
+
+    if args.offline && (cached.is_none() || cached_curves.is_none()) {
+        if args.explain {
+            detail!("{}: skipped, not in the cache", map_path.display());
+        }
+        return ProcessedMap {
+            not_cached: true,
+            ..Default::default()
+        };
+    }
+
+    let parsed =
+        match cached.is_none() || cached_curves.is_none() || needs_parsed_map(args, metric_expr) {
+            true => match read_map(files, map, &map_path, !edited) {
+                Some(map_pp) => Some(map_pp),
+                None => return ProcessedMap::default(),
+            },
+            false => None,
+        };
+
+    // The .osu file was edited after osu! read it, or osu!.db is out of date. Calculating it
+    // would give attributes of the wrong mode, so it is skipped, but not silently.
+    let listed_mode = listed_game_mode(map.mode);
+    if let Some(map_pp) = parsed.as_ref().filter(|m| m.mode != listed_mode) {
+        skipped!(
+            "Skipping {} ({}), osu!.db lists it as {:?} but the .osu file is {:?}",
+            map_path.display(),
+            map.hash.as_deref().unwrap_or("no hash"),
+            listed_mode,
+            map_pp.mode
+        );
+        return ProcessedMap {
+            mode_mismatch: true,
+            ..Default::default()
+        };
+    }
+
+    if let (Some(max), Some(map_pp)) = (args.max_break_fraction, &parsed) {
+        let fraction = break_fraction(map_pp);
+        if fraction > max {
+            if args.explain {
+                detail!(
+                    "{}: skipped, {:.0}% of the map are breaks",
+                    map_path.display(),
+                    fraction * 100f64
+                );
+            }
+            return ProcessedMap {
+                break_heavy: true,
+                ..Default::default()
+            };
+        }
+    }
+
+    // --mode conflicts with --cache, so these maps are always parsed.
+    if let Some(mode) = args.mode {
+        let map_pp = parsed.as_ref().unwrap();
+        return process_in_mode(
+            args,
+            mod_combinations,
+            below_min_pp,
+            map,
+            &map_path,
+            map_pp,
+            mode,
+        );
+    }
+
+    let attributes = cached.unwrap_or_else(|| {
+        let map_pp = parsed.as_ref().unwrap();
+        let window = Window::new(args).map(|window| (window, window.part_of(map_pp)));
+        let calculate = |map_pp: &rosu_pp::Beatmap, mods: u32| {
+            let calculation = map_pp
+                .pp()
+                .mods(mods)
+                .accuracy(play.accuracy)
+                .n_misses(play.misses);
+            let calculation = match play.combo {
+                Some(combo) => calculation.combo(combo),
+                None => calculation,
+            };
+            match calculation.calculate() {
+                PerformanceAttributes::Osu(pp) => Some(pp),
+                _ => None,
+            }
+        };
+
+        mod_combinations
+            .iter()
+            .filter_map(|&mods| {
+                let pp = calculate(map_pp, mods)?;
+                let window_pp = match &window {
+                    Some((_, window_map)) => Some(calculate(window_map, mods)?),
+                    None => None,
+                };
+                // The difficulty doesn't change with misses, so only the performance is
+                // calculated again.
+                let miss_pp = needs_miss_pp(args).then(|| {
+                    let miss_play = play.with_miss();
+                    let calculation = OsuPP::new(map_pp)
+                        .attributes(pp.difficulty.clone())
+                        .mods(mods)
+                        .accuracy(miss_play.accuracy)
+                        .n_misses(miss_play.misses);
+                    match miss_play.combo {
+                        Some(combo) => calculation.combo(combo),
+                        None => calculation,
+                    }
+                    .calculate()
+                });
+                if let Some(cache) = cache {
+                    cache.insert(map, mods, play, None, &pp);
+                    if let (Some((window, _)), Some(window_pp)) = (&window, &window_pp) {
+                        cache.insert(map, mods, play, Some(*window), window_pp);
+                    }
+                    if let Some(miss_pp) = &miss_pp {
+                        cache.insert(map, mods, play.with_miss(), None, miss_pp);
+                    }
+                }
+                Some((mods, pp, window_pp, miss_pp))
+            })
+            .collect()
+    });
+
+    let mut curves = cached_curves.unwrap_or_else(|| {
+        let map_pp = parsed.as_ref().unwrap();
+        mod_combinations
+            .iter()
+            .map(|&mods| {
+                let curve = difficulty_curve(map_pp, mods).unwrap_or_default();
+                if let Some(cache) = cache {
+                    cache.insert_curve(map, mods, &curve);
+                }
+                (mods, curve)
+            })
+            .collect()
+    });
+
+    let mut processed = ProcessedMap::default();
+
+    for (mods, pp, window_pp, miss_pp) in attributes {
+        if args.min_pp.is_some_and(|min_pp| pp.pp < min_pp) {
+            below_min_pp.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        // Without --strict these maps only end up without a ratio, which skips them in the ratio
+        // groupings.
+        if output::strict() && pp.pp <= 0.0 {
+            skipped!("{} {} has no pp", map_path.display(), mods::name(mods));
+        }
+
+        // osu! only calculates the common mod combinations, the others are checked against the
+        // star rating that was just calculated.
+        let stars = listed_stars(map, mods).unwrap_or(pp.difficulty.stars);
+        if !args.mods.is_empty() && stars < args.min_star_rating {
+            continue;
+        }
+
+        let calculation = MapCalculation {
+            map,
+            path: &map_path,
+            parsed: parsed.as_ref(),
+            mods,
+            pp,
+            window_pp,
+            miss_pp,
+            curve: curves.remove(&mods).filter(|curve| !curve.is_empty()),
+        };
+        // The pp based metrics come from the calculation above, which every grouping needs anyway,
+        // so there is no cheaper metadata-only path to keep them out of.
+        if args.export_metrics.is_some() {
+            processed.metrics.push(map_metrics(args, &calculation));
+        }
+
+        let grouped = GroupedMap {
+            map,
+            stars: calculation.pp.difficulty.stars,
+        };
+        // Spreads are only known once the whole set is, so these are put in their bucket later.
+        if args.single_collection.is_some() || groups_by_set(args) {
+            let key = BucketKey {
+                mods,
+                star_band: None,
+                bucket: 0,
+                merged_through: None,
+            };
+            if args.explain && !groups_by_set(args) {
+                detail!(
+                    "{} {}: -> {}",
+                    map_path.display(),
+                    mods::name(mods),
+                    bucket_name(args, key)
+                );
+            }
+            processed.buckets.push((key, grouped));
+            continue;
+        }
+
+        let Some(value) = map_value(args, metric_expr, &calculation) else {
+            if args.explain {
+                detail!(
+                    "{} {}: skipped, the map has no value to group by",
+                    map_path.display(),
+                    mods::name(mods)
+                );
+            }
+            continue;
+        };
+
+        let key = BucketKey {
+            mods,
+            star_band: matches!(args.group_by, GroupBy::StarsAndRatio)
+                .then(|| (stars / args.star_precision).floor() as i32),
+            bucket: (value / bucket_precision(args)).floor() as i32,
+            merged_through: None,
+        };
+        if args.explain {
+            explain(args, &calculation, value, key);
+        }
+
+        processed.buckets.push((key, grouped));
+    }
+
+    processed
+}
+
+/// Prints how the value of the map was turned into its bucket, for --explain.
+fn explain(args: &Args, calculation: &MapCalculation, value: f64, key: BucketKey) {
+    let pp = calculation.window_pp.as_ref().unwrap_or(&calculation.pp);
+    detail!(
+        "{} {}: aim pp {:.2}, speed pp {:.2}, value {value:.3}, floor({value:.3} / {}) = {} -> {}",
+        calculation.path.display(),
+        mods::name(calculation.mods),
+        pp.pp_aim,
+        pp.pp_speed,
+        bucket_precision(args),
+        key.bucket,
+        bucket_name(args, key)
+    );
+}
+
+/// With --strict and `check_hash` the file also has to have the hash osu!.db lists for it, which
+/// isn't the case when it was edited or replaced since osu! last read it.
+fn read_map(
+    files: &MapFiles,
+    map: &Beatmap,
+    path: &Path,
+    check_hash: bool,
+) -> Option<rosu_pp::Beatmap> {
+    let archived = files
+        .archived
+        .zip(map.hash.as_ref())
+        .and_then(|(archived, hash)| archived.get(hash));
+    let bytes = match archived {
+        Some(bytes) => Cow::Borrowed(bytes.as_slice()),
+        None => match files.io_limit.read(path) {
+            Ok(bytes) => Cow::Owned(bytes),
+            Err(why) => {
+                skipped!("Error while reading {}: {}", path.display(), why);
+                return None;
+            }
+        },
+    };
+
+    if output::strict() && check_hash {
+        let hash = format!("{:x}", md5::compute(&bytes));
+        if map.hash.as_ref().is_some_and(|expected| *expected != hash) {
+            skipped!(
+                "{} has the hash {hash}, but osu!.db lists {}",
+                path.display(),
+                map.hash.as_deref().unwrap_or_default()
+            );
+        }
+    }
+
+    match rosu_pp::Beatmap::from_bytes(&bytes) {
+        Ok(map) => Some(map),
+        Err(why) => {
+            skipped!("Error while parsing {}: {}", path.display(), why);
+            None
+        }
+    }
+}
+
+/// The star rating osu! calculated for the mod combination, if it did.
+/// [`process_map`] for the maps of another --mode, which have no aim and speed pp to group by.
+/// Standard maps are calculated as converts to it.
+fn process_in_mode<'a>(
+    args: &Args,
+    mod_combinations: &[u32],
+    below_min_pp: &AtomicUsize,
+    map: &'a Beatmap,
+    map_path: &Path,
+    map_pp: &rosu_pp::Beatmap,
+    mode: ConvertMode,
+) -> ProcessedMap<'a> {
+    let play = Play::new(args);
+    let converted = map_pp.convert_mode(mode.game_mode());
+    let mut processed = ProcessedMap::default();
+
+    for &mods in mod_combinations {
+        let calculation = map_pp
+            .pp()
+            .mode(mode.game_mode())
+            .mods(mods)
+            .accuracy(play.accuracy)
+            .n_misses(play.misses);
+        let attributes = match play.combo {
+            Some(combo) => calculation.combo(combo),
+            None => calculation,
+        }
+        .calculate();
+
+        if args.min_pp.is_some_and(|min_pp| attributes.pp() < min_pp) {
+            below_min_pp.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        if output::strict() && attributes.pp() <= 0.0 {
+            skipped!("{} {} has no pp", map_path.display(), mods::name(mods));
+        }
+        let stars = listed_mode_stars(args, map, mods).unwrap_or(attributes.stars());
+        if !args.mods.is_empty() && stars < args.min_star_rating {
+            continue;
+        }
+
+        let value = match args.group_by {
+            _ if args.single_collection.is_some() => Some(0.0),
+            GroupBy::LengthBand => length_band(args, map, mods).map(f64::from),
+            GroupBy::Density => {
+                note_density_percentile(args, &converted).map(|density| density * mods.clock_rate())
+            }
+            GroupBy::Sv => average_slider_velocity(&converted),
+            // Converts get their keycount from the conversion, the mods don't change it.
+            GroupBy::Cs if groups_by_keys(args) => Some(converted.cs as f64),
+            GroupBy::Cs => Some(circle_size(map, mods)),
+            // Rejected by check_mode before osu!.db is read.
+            _ => unreachable!(),
+        };
+        let Some(value) = value else {
+            if args.explain {
+                detail!(
+                    "{} {}: skipped, the map has no value to group by",
+                    map_path.display(),
+                    mods::name(mods)
+                );
+            }
+            continue;
+        };
+
+        let key = BucketKey {
+            mods,
+            star_band: None,
+            bucket: (value / bucket_precision(args)).floor() as i32,
+            merged_through: None,
+        };
+        if args.explain {
+            detail!(
+                "{} {}: {} stars {:.2}, pp {:.2}, value {value:.3} -> {}",
+                map_path.display(),
+                mods::name(mods),
+                mode.name(),
+                attributes.stars(),
+                attributes.pp(),
+                bucket_name(args, key)
+            );
+        }
+
+        let grouped = GroupedMap {
+            map,
+            stars: attributes.stars(),
+        };
+        processed.buckets.push((key, grouped));
+    }
+
+    processed
+}
+
+/// The mode rosu-pp parses a map of this osu!.db mode as.
+fn listed_game_mode(mode: Mode) -> GameMode {
+    match mode {
+        Mode::Standard => GameMode::Osu,
+        Mode::Taiko => GameMode::Taiko,
+        Mode::CatchTheBeat => GameMode::Catch,
+        Mode::Mania => GameMode::Mania,
+    }
+}
+
+fn listed_stars(map: &Beatmap, mods: u32) -> Option<f64> {
+    find_stars(&map.std_ratings, mods)
+}
+
+/// Like [`listed_stars`], from the ratings of --mode. osu! rates standard maps in every mode, so
+/// this is also the star rating of a convert.
+fn listed_mode_stars(args: &Args, map: &Beatmap, mods: u32) -> Option<f64> {
+    find_stars(mode_ratings(args, map), mods)
+}
+
+fn mode_ratings<'a>(args: &Args, map: &'a Beatmap) -> &'a [(ModSet, f64)] {
+    match args.mode {
+        None => &map.std_ratings,
+        Some(ConvertMode::Taiko) => &map.taiko_ratings,
+        Some(ConvertMode::Mania) => &map.mania_ratings,
+        Some(ConvertMode::Catch) => &map.ctb_ratings,
+    }
+}
+
+fn find_stars(ratings: &[(ModSet, f64)], mods: u32) -> Option<f64> {
+    let bits = mods::star_rating_bits(mods);
+    ratings
+        .iter()
+        .find_map(|(mods, stars)| (mods.0 == bits).then_some(*stars))
+}
+
+/// Custom metrics can't refer to the pp with a miss, so it is only calculated for the grouping.
+fn needs_miss_pp(args: &Args) -> bool {
+    args.single_collection.is_none()
+        && args.metric_expr.is_none()
+        && matches!(args.group_by, GroupBy::MissSensitivity)
+}
+
+fn needs_curve(args: &Args) -> bool {
+    args.single_collection.is_none()
+        && args.metric_expr.is_none()
+        && matches!(args.group_by, GroupBy::DensityCurveShape)
+}
+
+/// The aim and speed strain of every section of the map added up and averaged into
+/// [`CURVE_SEGMENTS`] equally long parts, so the curves of maps of any length can be compared and
+/// cached. Maps with fewer sections than that have no curve.
+fn difficulty_curve(map_pp: &rosu_pp::Beatmap, mods: u32) -> Option<Vec<f64>> {
+    let strains = OsuStars::new(map_pp).mods(mods).strains();
+    let sections: Vec<f64> = strains
+        .aim
+        .iter()
+        .zip(&strains.speed)
+        .map(|(aim, speed)| aim + speed)
+        .collect();
+    if sections.len() < CURVE_SEGMENTS {
+        return None;
+    }
+
+    let curve = (0..CURVE_SEGMENTS)
+        .map(|segment| {
+            let part = &sections[segment * sections.len() / CURVE_SEGMENTS
+                ..(segment + 1) * sections.len() / CURVE_SEGMENTS];
+            part.iter().sum::<f64>() / part.len() as f64
+        })
+        .collect();
+    Some(curve)
+}
+
+/// The bucket of [`CURVE_SHAPE_NAMES`] the curve falls into. The trend is the average of the last
+/// quarter minus the one of the first quarter, relative to the average of the whole curve. A trend
+/// of at least --curve-sensitivity either way makes the map ascending or descending. Without a
+/// trend, the map is bursty when the strain varies by more than --curve-burstiness around its
+/// average and flat otherwise.
+fn curve_shape(args: &Args, curve: &[f64]) -> Option<i32> {
+    let average = |part: &[f64]| part.iter().sum::<f64>() / part.len() as f64;
+    let mean = average(curve);
+    if mean <= 0.0 {
+        return None;
+    }
+
+    let quarter = curve.len() / 4;
+    let trend = (average(&curve[curve.len() - quarter..]) - average(&curve[..quarter])) / mean;
+    let deviation = (curve
+        .iter()
+        .map(|strain| (strain - mean).powi(2))
+        .sum::<f64>()
+        / curve.len() as f64)
+        .sqrt()
+        / mean;
+
+    let shape = match trend {
+        trend if trend >= args.curve_sensitivity => 0,
+        trend if trend <= -args.curve_sensitivity => 2,
+        _ if deviation > args.curve_burstiness => 3,
+        _ => 1,
+    };
+    Some(shape)
+}
+
+/// Whether the grouping or --max-break-fraction look at the map itself, and not only at its
+/// performance attributes. --window-metric needs the map as well, but its attributes are cached
+/// like the normal ones.
+fn needs_parsed_map(args: &Args, metric_expr: Option<&Node>) -> bool {
+    let grouping = args.single_collection.is_none()
+        && (metric_expr.is_some() || matches!(args.group_by, GroupBy::Sv | GroupBy::Density));
+    grouping || args.max_break_fraction.is_some()
+}
+
+/// The time in breaks divided by the time from the first to the last note. Breaks can only be
+/// between notes, so this stays between 0 and 1.
+fn break_fraction(map_pp: &rosu_pp::Beatmap) -> f64 {
+    let (Some(first), Some(last)) = (map_pp.hit_objects.first(), map_pp.hit_objects.last()) else {
+        return 0.0;
+    };
+    let length = last.start_time - first.start_time;
+    match length > 0.0 {
+        true => (map_pp.total_break_time() / length).min(1.0),
+        false => 0.0,
+    }
+}
+
+/// An edited version of the map in `--osu-override-dir`, named after the hash of the original.
+fn override_path(args: &Args, map: &Beatmap) -> Option<PathBuf> {
+    let path = args
+        .osu_override_dir
+        .as_ref()?
+        .join(format!("{}.osu", map.hash.as_ref()?));
+    path.is_file().then_some(path)
+}
+
+/// The value a map is bucketed by, or `None` if it can't be grouped in the current mode.
+fn map_value(args: &Args, metric_expr: Option<&Node>, calculation: &MapCalculation) -> Option<f64> {
+    if let Some(expr) = metric_expr {
+        let context = metric_context(calculation);
+        return match expr.eval_number_with_context(&context) {
+            Ok(value) => Some(value),
+            Err(why) => {
+                skipped!(
+                    "Error while evaluating metric for {}: {}",
+                    calculation.path.display(),
+                    why
+                );
+                None
+            }
+        };
+    }
+
+    let pp = &calculation.pp;
+    match args.group_by {
+        // Maps without any pp can't have a ratio, they are skipped like maps without a value in
+        // the other modes.
+        GroupBy::FirstSeconds => Some(calculation.window_pp.as_ref()?.difficulty.stars),
+        GroupBy::Aim | GroupBy::StarsAndRatio => {
+            let pp = calculation.window_pp.as_ref().unwrap_or(pp);
+            Some(length_weighted(args, calculation, aim_aspect(args, pp)) * 100f64)
+                .filter(|ratio| ratio.is_finite())
+        }
+        GroupBy::Acc => Some(pp.pp_acc / pp.pp * 100f64).filter(|ratio| ratio.is_finite()),
+        GroupBy::Sv => average_slider_velocity(calculation.parsed?),
+        GroupBy::Density => note_density_percentile(args, calculation.parsed?)
+            .map(|density| density * calculation.mods.clock_rate()),
+        GroupBy::LengthBand => length_band(args, calculation.map, calculation.mods).map(f64::from),
+        GroupBy::DensityCurveShape => {
+            curve_shape(args, calculation.curve.as_deref()?).map(f64::from)
+        }
+        GroupBy::Cs => Some(circle_size(calculation.map, calculation.mods)),
+        GroupBy::MissSensitivity => {
+            let miss_pp = calculation.miss_pp.as_ref()?;
+            Some((pp.pp - miss_pp.pp) / pp.pp * 100f64).filter(|drop| drop.is_finite())
+        }
+        // Put in their bucket by set in group_maps_by, never by a value of their own.
+        GroupBy::DiffSpread => None,
+    }
+}
+
+/// Mania reads the circle size as the number of keys, so every keycount is its own group.
+fn groups_by_keys(args: &Args) -> bool {
+    matches!(args.mode, Some(ConvertMode::Mania)) && matches!(args.group_by, GroupBy::Cs)
+}
+
+fn bucket_precision(args: &Args) -> f64 {
+    match (&args.metric_expr, args.group_by) {
+        (None, GroupBy::Sv) => args.sv_precision,
+        (None, GroupBy::Density) => args.density_precision,
+        (None, GroupBy::MissSensitivity) => args.miss_precision,
+        (None, GroupBy::DiffSpread) => args.spread_precision,
+        (None, GroupBy::FirstSeconds) => args.star_precision,
+        (None, GroupBy::LengthBand | GroupBy::DensityCurveShape) => 1.0,
+        (None, GroupBy::Cs) if groups_by_keys(args) => 1.0,
+        (None, GroupBy::Cs) => args.cs_precision,
+        _ => args.ratio_precision,
+    }
+}
+
+/// Percentages only go up to 100, so a ratio precision that doesn't divide it evenly leaves a
+/// narrower top bucket (eg. 98-100 with a precision of 7). This is allowed, but worth pointing out.
+fn warn_uneven_ratio_precision(args: &Args) {
+    const EVEN_PRECISIONS: [f64; 10] = [0.5, 1.0, 2.0, 2.5, 4.0, 5.0, 10.0, 20.0, 25.0, 50.0];
+
+    if args.metric_expr.is_some() || !matches!(args.group_by, GroupBy::Aim | GroupBy::Acc) {
+        return;
+    }
+
+    let precision = args.ratio_precision;
+    let buckets = 100.0 / precision;
+    if (buckets - buckets.round()).abs() < 1e-9 {
+        return;
+    }
+
+    let lower = EVEN_PRECISIONS.iter().rev().find(|&&p| p < precision);
+    let higher = EVEN_PRECISIONS.iter().find(|&&p| p > precision);
+    let suggestions = [lower, higher]
+        .into_iter()
+        .flatten()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(" or ");
+
+    let top = (buckets.floor() * precision * 100.0).round() / 100.0;
+    problem!(
+        "Warning: --ratio-precision {precision} doesn't divide 100 evenly, the last bucket only covers {top}-100"
+    );
+    if !suggestions.is_empty() {
+        problem!("Consider using {suggestions} for equally sized buckets");
+    }
+}
+
+/// The buckets passed to `--only-buckets`. Each value has to be the start of a bucket at the
+/// current precision, otherwise it would silently select the bucket it falls into.
+fn selected_buckets(args: &Args) -> Option<HashSet<i32>> {
+    if args.only_buckets.is_empty() {
+        return None;
+    }
+    let Some(argument) = bucket_precision_argument(args) else {
+        panic!("--only-buckets needs a grouping with a precision, length bands can't be selected");
+    };
+    if let (None, GroupBy::StarsAndRatio) = (&args.metric_expr, args.group_by) {
+        panic!("--only-buckets can't select buckets of stars-and-ratio, which also depend on the star rating");
+    }
+
+    let precision = bucket_precision(args);
+    let buckets = args
+        .only_buckets
+        .iter()
+        .map(|&value| {
+            let bucket = (value / precision).round();
+            if (bucket * precision - value).abs() > 1e-6 {
+                panic!(
+                    "--only-buckets {value} is not the start of a bucket with {argument} {precision}, use a multiple of {precision}"
+                );
+            }
+            bucket as i32
+        })
+        .collect();
+
+    Some(buckets)
+}
+
+/// The name of the argument that sets [`bucket_precision`]. Length bands have fixed boundaries
+/// instead of a precision.
+fn bucket_precision_argument(args: &Args) -> Option<&'static str> {
+    match (&args.metric_expr, args.group_by) {
+        (None, GroupBy::Sv) => Some("--sv-precision"),
+        (None, GroupBy::Density) => Some("--density-precision"),
+        (None, GroupBy::MissSensitivity) => Some("--miss-precision"),
+        (None, GroupBy::DiffSpread) => Some("--spread-precision"),
+        (None, GroupBy::FirstSeconds) => Some("--star-precision"),
+        (None, GroupBy::LengthBand | GroupBy::DensityCurveShape) => None,
+        (None, GroupBy::Cs) if groups_by_keys(args) => None,
+        (None, GroupBy::Cs) => Some("--cs-precision"),
+        _ => Some("--ratio-precision"),
+    }
+}
+
+fn map_metrics(args: &Args, calculation: &MapCalculation) -> MapMetrics {
+    let map = calculation.map;
+    let pp = &calculation.pp;
+    let clock_rate = calculation.mods.clock_rate();
+    let share = |part: f64| part / pp.pp * 100f64;
+
+    MapMetrics {
+        hash: map.hash.clone(),
+        artist: map.artist_unicode.clone().or(map.artist_ascii.clone()),
+        title: map.title_unicode.clone().or(map.title_ascii.clone()),
+        version: map.difficulty_name.clone(),
+        mods: mods::name(calculation.mods),
+        stars: pp.difficulty.stars,
+        pp: pp.pp,
+        aim_ratio: aim_aspect(args, pp) * 100f64,
+        speed_ratio: share(pp.pp_speed),
+        acc_ratio: share(pp.pp_acc),
+        flashlight_ratio: share(pp.pp_flashlight),
+        bpm: main_bpm(map).map(|bpm| bpm * clock_rate),
+        drain_time: map.drain_time as f64 / clock_rate,
+        total_length: map.total_time as f64 / 1000f64 / clock_rate,
+        ar: pp.difficulty.ar,
+        od: pp.difficulty.od,
+        cs: circle_size(calculation.map, calculation.mods),
+        max_combo: pp.difficulty.max_combo,
+    }
+}
+
+/// The BPM that lasts the longest, from the uninherited timing points in osu!.db. These store
+/// the milliseconds per beat, which osu_db calls `bpm`.
+fn main_bpm(map: &Beatmap) -> Option<f64> {
+    let points: Vec<_> = map
+        .timing_points
+        .iter()
+        .filter(|point| point.inherits && point.bpm > 0f64)
+        .collect();
+    let end = map.total_time as f64;
+
+    let mut durations: Vec<(f64, f64)> = Vec::new();
+    for (index, point) in points.iter().enumerate() {
+        let next = points.get(index + 1).map_or(end, |next| next.offset);
+        let bpm = 60_000f64 / point.bpm;
+        match durations
+            .iter_mut()
+            .find(|(existing, _)| (existing - bpm).abs() < 1e-3)
+        {
+            Some((_, duration)) => *duration += next - point.offset,
+            None => durations.push((bpm, next - point.offset)),
+        }
+    }
+
+    durations
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(bpm, _)| bpm)
+}
+
+/// The circle size as played, HR makes circles 30% smaller (up to CS 10) and EZ halves the size.
+fn circle_size(map: &Beatmap, mods: u32) -> f64 {
+    let cs = map.circle_size as f64;
+    match (mods.hr(), mods.ez()) {
+        (true, _) => (cs * 1.3).min(10.0),
+        (_, true) => cs * 0.5,
+        _ => cs,
+    }
+}
+
+/// The index of the length band the map falls into, in seconds of playback so rate changing mods
+/// move maps between bands.
+fn length_band(args: &Args, map: &Beatmap, mods: u32) -> Option<i32> {
+    let length = match args.length_source {
+        LengthSource::Drain => map.drain_time,
+        LengthSource::Total => map.total_time / 1000,
+    };
+
+    if length == 0 {
+        return (!args.skip_unknown_length).then_some(UNKNOWN_LENGTH_BAND);
+    }
+
+    let length = length as f64 / mods.clock_rate();
+    let band = args
+        .length_bands
+        .iter()
+        .take_while(|&&boundary| length >= boundary as f64)
+        .count();
+    Some(band as i32)
+}
+
+fn length_band_name(args: &Args, band: i32) -> String {
+    if band == UNKNOWN_LENGTH_BAND {
+        return "Unknown length".to_string();
+    }
+
+    let band = band as usize;
+    let format_length = |seconds: u32| format!("{}:{:02}", seconds / 60, seconds % 60);
+    let range = match (band.checked_sub(1), args.length_bands.get(band)) {
+        (None, Some(&upper)) => format!("<{}", format_length(upper)),
+        (Some(lower), Some(&upper)) => format!(
+            "{}-{}",
+            format_length(args.length_bands[lower]),
+            format_length(upper)
+        ),
+        (Some(lower), None) => format!("{}+", format_length(args.length_bands[lower])),
+        (None, None) => "any length".to_string(),
+    };
+
+    match args.length_bands.len() + 1 == LENGTH_BAND_NAMES.len() {
+        true => format!("{} ({range})", LENGTH_BAND_NAMES[band]),
+        false => format!("Length ({range})"),
+    }
+}
+
+/// Averages the effective slider velocity (base slider multiplier times the inherited timing
+/// point multiplier) over all sliders, so sections with many sliders weigh more. Maps without
+/// sliders have no meaningful slider velocity and return `None`.
+fn average_slider_velocity(map_pp: &rosu_pp::Beatmap) -> Option<f64> {
+    let (count, total) = map_pp
+        .hit_objects
+        .iter()
+        .filter(|h| h.is_slider())
+        .map(|h| {
+            map_pp
+                .difficulty_point_at(h.start_time)
+                .map_or(DifficultyPoint::DEFAULT_SLIDER_VEL, |p| p.slider_vel)
+        })
+        .fold((0, 0f64), |(count, total), sv| (count + 1, total + sv));
+
+    (count > 0).then(|| map_pp.slider_mult * total / count as f64)
+}
+
+/// The part of the map with the most hit objects starting within `seconds`, as its own map. The
+/// window starts at a hit object and is moved over the map one object at a time, which finds
+/// the densest window in a single pass.
+fn densest_window(map_pp: &rosu_pp::Beatmap, seconds: f64) -> rosu_pp::Beatmap {
+    let objects = &map_pp.hit_objects;
+    let length = seconds * 1000f64;
+
+    let mut end = 0;
+    let mut densest = 0..0;
+    for start in 0..objects.len() {
+        while end < objects.len() && objects[end].start_time < objects[start].start_time + length {
+            end += 1;
+        }
+        if end - start > densest.len() {
+            densest = start..end;
+        }
+    }
+
+    sub_map(map_pp, densest)
+}
+
+/// The hit objects starting within `seconds` of the first one, as their own map. Maps shorter
+/// than that are kept whole.
+fn opening(map_pp: &rosu_pp::Beatmap, seconds: f64) -> rosu_pp::Beatmap {
+    let objects = &map_pp.hit_objects;
+    let Some(first) = objects.first() else {
+        return map_pp.clone();
+    };
+    let cutoff = first.start_time + seconds * 1000f64;
+    let end = objects.partition_point(|h| h.start_time < cutoff);
+
+    sub_map(map_pp, 0..end)
+}
+
+/// A map of only some of the hit objects. The timing of the map is kept, so sliders are unchanged.
+fn sub_map(map_pp: &rosu_pp::Beatmap, range: Range<usize>) -> rosu_pp::Beatmap {
+    let hit_objects = map_pp.hit_objects[range.clone()].to_vec();
+    let count = |kind: fn(&rosu_pp::parse::HitObject) -> bool| {
+        hit_objects.iter().filter(|h| kind(h)).count() as u32
+    };
+    rosu_pp::Beatmap {
+        n_circles: count(rosu_pp::parse::HitObject::is_circle),
+        n_sliders: count(rosu_pp::parse::HitObject::is_slider),
+        n_spinners: count(rosu_pp::parse::HitObject::is_spinner),
+        sounds: map_pp.sounds.get(range).unwrap_or_default().to_vec(),
+        hit_objects,
+        ..map_pp.clone()
+    }
+}
+
+/// For every hit object, counts the objects starting within the next `--density-window` seconds
+/// and returns the `--density-percentile` of these local densities in notes per second. This
+/// walks all hit objects once, which is cheap compared to the pp calculation.
+fn note_density_percentile(args: &Args, map_pp: &rosu_pp::Beatmap) -> Option<f64> {
+    let times: Vec<f64> = map_pp.hit_objects.iter().map(|h| h.start_time).collect();
+    if times.is_empty() {
+        return None;
+    }
+
+    let window = args.density_window * 1000f64;
+
+    let mut end = 0;
+    let mut densities: Vec<f64> = times
+        .iter()
+        .enumerate()
+        .map(|(start, time)| {
+            while end < times.len() && times[end] < time + window {
+                end += 1;
+            }
+            (end - start) as f64 / args.density_window
+        })
+        .collect();
+
+    densities.sort_by(f64::total_cmp);
+
+    let rank = (args.density_percentile / 100f64 * densities.len() as f64).ceil() as usize;
+    densities.get(rank.clamp(1, densities.len()) - 1).copied()
+}
+
+fn within_length_limits(args: &Args, map: &Beatmap) -> bool {
+    let drain = map.drain_time;
+    let length = map.total_time / 1000;
+
+    args.min_drain.is_none_or(|min| drain >= min)
+        && args.max_drain.is_none_or(|max| drain <= max)
+        && args.min_length.is_none_or(|min| length >= min)
+        && args.max_length.is_none_or(|max| length <= max)
+}
+
+/// Drops maps whose folder contains a storyboard or video, for `--no-storyboard` and `--no-video`.
+/// Only the files in the folder are checked: a storyboard is a `.osb` file and a video is any
+/// common video format. Storyboards written into a single `.osu` file aren't detected.
+fn filter_media<'a>(args: &Args, maps: Vec<&'a Beatmap>) -> Vec<&'a Beatmap> {
+    if !args.no_storyboard && !args.no_video {
+        return maps;
+    }
+
+    // All difficulties of a set share a folder, so every folder only has to be listed once.
+    let mut folders: HashMap<&str, (bool, bool)> = HashMap::new();
+    let count = maps.len();
+
+    let maps: Vec<&Beatmap> = maps
+        .into_iter()
+        .filter(|map| {
+            let folder = map.folder_name.as_deref().unwrap_or_default();
+            let (storyboard, video) = *folders
+                .entry(folder)
+                .or_insert_with(|| folder_media(&args.osu_path.join("Songs").join(folder)));
+
+            let excluded = (args.no_storyboard && storyboard) || (args.no_video && video);
+            !excluded
+        })
+        .collect();
+
+    status!(
+        "Skipped {} maps with a storyboard or video",
+        count - maps.len()
+    );
+    maps
+}
+
+/// Keeps only the first map for every hash. The same map can be in osu!.db twice when the set was
+/// downloaded into two folders, and both copies would otherwise be calculated and end up in the
+/// same collection.
+fn remove_duplicate_hashes<'a>(args: &Args, maps: Vec<&'a Beatmap>) -> Vec<&'a Beatmap> {
+    let mut seen = HashSet::new();
+    let count = maps.len();
+
+    let maps: Vec<&Beatmap> = maps
+        .into_iter()
+        .filter(|map| {
+            let Some(hash) = map.hash.as_deref() else {
+                return true;
+            };
+            let first = seen.insert(hash);
+            if !first && args.explain {
+                detail!(
+                    "Skipping {}, the same map is in another folder",
+                    map.folder_name.as_deref().unwrap_or_default()
+                );
+            }
+            first
+        })
+        .collect();
+
+    if maps.len() < count {
+        status!(
+            "Skipped {} maps that are in osu!.db more than once",
+            count - maps.len()
+        );
+    }
+    maps
+}
+
+/// Whether the folder contains a storyboard and a video.
+fn folder_media(folder: &Path) -> (bool, bool) {
+    const VIDEO_EXTENSIONS: [&str; 7] = ["avi", "flv", "m4v", "mkv", "mov", "mp4", "webm"];
+
+    let extensions: Vec<String> = std::fs::read_dir(folder)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| Some(entry.path().extension()?.to_str()?.to_lowercase()))
+        .collect();
+
+    (
+        extensions.iter().any(|e| e == "osb"),
+        extensions
+            .iter()
+            .any(|e| VIDEO_EXTENSIONS.contains(&e.as_str())),
+    )
+}
+
+fn matches_version_filters(args: &Args, map: &Beatmap) -> bool {
+    let version = map
+        .difficulty_name
+        .as_deref()
+        .unwrap_or_default()
+        .to_lowercase();
+    let contains = |needle: &String| version.contains(&needle.to_lowercase());
+
+    !args.exclude_version_contains.iter().any(contains)
+        && (args.include_version_contains.is_empty()
+            || args.include_version_contains.iter().any(contains))
+}
+
+fn aim_aspect(args: &Args, pp: &OsuPerformanceAttributes) -> f64 {
+    let difficulty = &pp.difficulty;
+    let (aim, pp_aim) = match args.aim_includes_sliders {
+        true => (difficulty.aim, pp.pp_aim),
+        false => without_slider_aim(pp),
+    };
+    let offset = match (args.normalize, args.ratio_denominator) {
+        (None, RatioDenominator::AimSpeed) => return pp_aim / (pp_aim + pp.pp_speed),
+        (None, RatioDenominator::Total) => return pp_aim / pp.pp,
+        (Some(Normalize::Stars), _) => (aim - difficulty.speed) / difficulty.stars,
+        (Some(Normalize::Pp), _) => (pp_aim - pp.pp_speed) / pp.pp,
+    };
+
+    (0.5 + offset / 2f64).clamp(0f64, 1f64)
+}
+
+/// The aim stars and aim pp without the part that comes from sliders. The slider factor is the
+/// aim rating without sliders divided by the one with sliders, and the aim pp only depends on the
+/// aim rating through `(5 * max(1, aim / 0.0675) - 4) ^ 3`, so scaling that term gives the aim pp
+/// as if the map had no slider aim.
+fn without_slider_aim(pp: &OsuPerformanceAttributes) -> (f64, f64) {
+    let base = |aim: f64| (5f64 * (aim / 0.0675).max(1f64) - 4f64).powi(3);
+
+    let aim = pp.difficulty.aim;
+    let aim_without_sliders = aim * pp.difficulty.slider_factor;
+    (
+        aim_without_sliders,
+        pp.pp_aim * base(aim_without_sliders) / base(aim),
+    )
+}
+
+/// Multiplies the ratio by (drain time / 3 minutes) ^ strength, in seconds of playback so rate
+/// changing mods count. Maps without a known drain time keep their ratio.
+fn length_weighted(args: &Args, calculation: &MapCalculation, ratio: f64) -> f64 {
+    const REFERENCE_LENGTH: f64 = 180.0;
+
+    let drain = calculation.map.drain_time as f64 / calculation.mods.clock_rate();
+    if !args.length_weighted_ratio || drain <= 0.0 {
+        return ratio;
+    }
+
+    let factor = (drain / REFERENCE_LENGTH).powf(args.length_weight_strength);
+    (ratio * factor).clamp(0f64, 1f64)
+}
+
+/// Keeps only one difficulty per beatmapset in every bucket. Unsubmitted maps don't have a
+/// beatmapset id, so their folder is used instead.
+fn collapse_beatmapsets(args: &Args, groups: &mut BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    let mut removed = 0;
+
+    for maps in groups.values_mut() {
+        // Sets keep the place of their first map, so maps with the same star rating stay in
+        // listing order after sorting.
+        let mut positions: HashMap<(i32, Option<&str>), usize> = HashMap::new();
+        let mut representatives: Vec<GroupedMap> = Vec::new();
+        let count = maps.len();
+
+        for grouped in maps.drain(..) {
+            match positions.entry(beatmapset(grouped.map)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(representatives.len());
+                    representatives.push(grouped);
+                }
+                Entry::Occupied(entry) => {
+                    let current = &mut representatives[*entry.get()];
+                    let replace = match args.per_set_pick {
+                        SetRepresentative::Highest => grouped.stars > current.stars,
+                        SetRepresentative::Lowest => grouped.stars < current.stars,
+                    };
+                    if replace {
+                        *current = grouped;
+                    }
+                }
+            }
+        }
+
+        maps.extend(representatives);
+        maps.sort_by(|a, b| a.stars.total_cmp(&b.stars));
+        removed += count - maps.len();
+    }
+
+    status!("Removed {removed} maps from the same beatmapset");
+}
+
+/// Merges buckets with fewer than `threshold` maps into a neighbour until every bucket is large
+/// enough or there is only one left. Buckets are only merged with the next or previous bucket of
+/// the same mods and star band, always the smaller of the two, so the result stays as even as
+/// possible. The first small bucket in order is merged first.
+fn merge_small_buckets(
+    args: &Args,
+    threshold: usize,
+    groups: &mut BTreeMap<BucketKey, Vec<GroupedMap>>,
+) {
+    if let (None, GroupBy::LengthBand) = (&args.metric_expr, args.group_by) {
+        panic!("--merge-threshold can't merge length bands, move the --length-bands boundaries instead");
+    }
+    if let (None, GroupBy::DensityCurveShape) = (&args.metric_expr, args.group_by) {
+        panic!("--merge-threshold can't merge curve shapes, they aren't ranges of a value");
+    }
+
+    // Buckets of the same mods and star band, in order.
+    let mut sequences: BTreeMap<(u32, Option<i32>), Vec<Bucket>> = BTreeMap::new();
+    for (key, maps) in std::mem::take(groups) {
+        sequences
+            .entry((key.mods, key.star_band))
+            .or_default()
+            .push((key, maps));
+    }
+
+    let mut merged = 0;
+    for buckets in sequences.values_mut() {
+        while buckets.len() > 1 {
+            let Some(index) = buckets.iter().position(|(_, maps)| maps.len() < threshold) else {
+                break;
+            };
+            let neighbour = match (index.checked_sub(1), buckets.get(index + 1)) {
+                (Some(previous), Some((_, next))) if next.len() < buckets[previous].1.len() => {
+                    index + 1
+                }
+                (Some(previous), _) => previous,
+                (None, _) => index + 1,
+            };
+
+            let (lower, upper) = (index.min(neighbour), index.max(neighbour));
+            let (upper_key, upper_maps) = buckets.remove(upper);
+            let (lower_key, lower_maps) = &mut buckets[lower];
+            lower_key.merged_through = Some(upper_key.merged_through.unwrap_or(upper_key.bucket));
+            lower_maps.extend(upper_maps);
+            merged += 1;
+        }
+    }
+
+    groups.extend(sequences.into_values().flatten());
+    status!("Merged {merged} buckets with fewer than {threshold} maps into their neighbours");
+}
+
+/// Combines the buckets covered by the same label into one, which is named after the label. The
+/// buckets of different mods and star bands stay apart.
+fn apply_labels(args: &Args, labels: &Labels, groups: &mut BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    let mut labelled: BTreeMap<(u32, Option<i32>, usize), Bucket> = BTreeMap::new();
+    let mut used = HashSet::new();
+    for (key, maps) in std::mem::take(groups) {
+        let Some((index, _)) = labels.find(key.bucket as f64 * bucket_precision(args)) else {
+            groups.insert(key, maps);
+            continue;
+        };
+        used.insert(index);
+
+        let (merged_key, merged_maps) = labelled
+            .entry((key.mods, key.star_band, index))
+            .or_insert((key, Vec::new()));
+        if key.bucket > merged_key.bucket {
+            merged_key.merged_through = Some(key.bucket);
+        }
+        merged_maps.extend(maps);
+    }
+    groups.extend(labelled.into_values());
+
+    for (index, label) in labels.iter().enumerate() {
+        if !used.contains(&index) {
+            problem!("Warning: no map fell into the label {}", label.name);
+        }
+    }
+}
+
+/// Drops maps until all buckets together hold at most `max_maps`. Within a bucket the maps with the
+/// lowest star rating are dropped first, the strategy decides how many each bucket loses.
+fn trim_to_total(args: &Args, max_maps: usize, groups: &mut BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    let total: usize = groups.values().map(Vec::len).sum();
+    if total <= max_maps {
+        return;
+    }
+
+    let mut keep: BTreeMap<BucketKey, usize> = match args.trim_strategy {
+        TrimStrategy::Proportional => {
+            // Round down first, then hand out the remaining slots to the buckets that lost the
+            // most to rounding.
+            let mut keep: BTreeMap<BucketKey, usize> = groups
+                .iter()
+                .map(|(key, maps)| (*key, maps.len() * max_maps / total))
+                .collect();
+            let mut remainders: Vec<(usize, BucketKey)> = groups
+                .iter()
+                .map(|(key, maps)| (maps.len() * max_maps % total, *key))
+                .collect();
+            remainders.sort_by_key(|(remainder, _)| std::cmp::Reverse(*remainder));
+
+            let leftover = max_maps - keep.values().sum::<usize>();
+            for (_, key) in remainders.into_iter().take(leftover) {
+                *keep.get_mut(&key).unwrap() += 1;
+            }
+            keep
+        }
+        TrimStrategy::HardestFirst => {
+            let mut stars: Vec<(f64, BucketKey)> = groups
+                .iter()
+                .flat_map(|(key, maps)| maps.iter().map(|m| (m.stars, *key)))
+                .collect();
+            stars.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut keep: BTreeMap<BucketKey, usize> = groups.keys().map(|k| (*k, 0)).collect();
+            for (_, key) in stars.into_iter().take(max_maps) {
+                *keep.get_mut(&key).unwrap() += 1;
+            }
+            keep
+        }
+        TrimStrategy::LargestFirst => {
+            let mut keep: BTreeMap<BucketKey, usize> = groups
+                .iter()
+                .map(|(key, maps)| (*key, maps.len()))
+                .collect();
+            for _ in max_maps..total {
+                let largest = keep
+                    .iter()
+                    .max_by_key(|(_, count)| **count)
+                    .map(|(k, _)| *k);
+                *keep.get_mut(&largest.unwrap()).unwrap() -= 1;
+            }
+            keep
+        }
+    };
+
+    status!("Trimming {total} maps down to {max_maps}");
+
+    for (key, maps) in groups.iter_mut() {
+        let keep = keep.remove(key).unwrap_or_default();
+        if keep >= maps.len() {
+            continue;
+        }
+
+        let name = collection_name(args, *key, maps);
+        let mut by_stars: Vec<usize> = (0..maps.len()).collect();
+        by_stars.sort_by(|&a, &b| maps[b].stars.total_cmp(&maps[a].stars));
+        let mut kept = vec![false; maps.len()];
+        for &index in &by_stars[..keep] {
+            kept[index] = true;
+        }
+
+        let mut kept = kept.into_iter();
+        maps.retain(|_| kept.next().unwrap());
+        status!("Trimmed {} maps from {name}", by_stars.len() - keep);
+    }
+
+    groups.retain(|_, maps| !maps.is_empty());
+}
+
+/// Puts the maps of every collection in a random order. The same seed always gives the same order
+/// for the same maps.
+fn shuffle_groups(args: &Args, groups: &mut BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    let seed = args.seed.unwrap_or_else(rand::random);
+    status!("Shuffling collections with seed {seed}");
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    for maps in groups.values_mut() {
+        maps.shuffle(&mut rng);
+    }
+}
+
+/// Prints how evenly the maps are spread across the buckets, measured by the coefficient of
+/// variation (standard deviation / mean) of the bucket sizes, and suggests a coarser precision if
+/// the spread is very uneven.
+fn report_bucket_balance(args: &Args, groups: &BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    if groups.len() < 2 {
+        return;
+    }
+
+    let sizes: Vec<f64> = groups.values().map(|maps| maps.len() as f64).collect();
+    let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+    let variance = sizes.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / sizes.len() as f64;
+    let variation = variance.sqrt() / mean;
+
+    status!(
+        "Bucket sizes vary by {:.0}% around the mean",
+        variation * 100f64
+    );
+
+    if variation > 1f64 {
+        match bucket_precision_argument(args) {
+            Some(argument) => status!(
+                "Buckets are very uneven, try {} {} to get fewer, fuller collections",
+                argument,
+                bucket_precision(args) * 2f64
+            ),
+            None => status!("Buckets are very uneven, try moving the --length-bands boundaries"),
+        }
+    }
+}
+
+/// Every star band gets its own set of ratio buckets, so stars-and-ratio quickly creates more
+/// collections than are useful in game.
+fn warn_star_band_growth(args: &Args, groups: &BTreeMap<BucketKey, Vec<GroupedMap>>) {
+    if args.metric_expr.is_some() || !matches!(args.group_by, GroupBy::StarsAndRatio) {
+        return;
+    }
+
+    let star_bands: HashSet<_> = groups.keys().map(|key| key.star_band).collect();
+    let ratio_buckets: HashSet<_> = groups.keys().map(|key| key.bucket).collect();
+    status!(
+        "Grouping by stars-and-ratio creates {} collections from {} star bands and {} ratio buckets",
+        groups.len(),
+        star_bands.len(),
+        ratio_buckets.len()
+    );
+
+    let sparse = groups.values().filter(|maps| maps.len() < 5).count();
+    if sparse * 2 > groups.len() {
+        problem!(
+            "Warning: {sparse} of these have fewer than 5 maps, try a larger --star-precision or --ratio-precision {} to get fewer, fuller collections",
+            args.ratio_precision * 2f64
+        );
+    }
+}
+
+/// Parses and validates the `--metric-expr` argument so that mistakes surface before any map is
+/// processed.
+fn parse_metric_expr(expr: &str) -> Node {
+    let node = evalexpr::build_operator_tree(expr)
+        .unwrap_or_else(|why| panic!("Invalid metric expression: {why}"));
+
+    for variable in node.iter_variable_identifiers() {
+        if !METRIC_VARIABLES.contains(&variable) {
+            panic!(
+                "Unknown variable {variable} in metric expression, available variables are: {}",
+                METRIC_VARIABLES.join(", ")
+            );
+        }
+    }
+
+    let mut context = HashMapContext::new();
+    for variable in METRIC_VARIABLES {
+        context
+            .set_value(variable.to_string(), Value::from_float(1f64))
+            .unwrap();
+    }
+    if let Err(why) = node.eval_number_with_context(&context) {
+        panic!("Metric expression does not evaluate to a number: {why}");
+    }
+
+    node
+}
+
+fn metric_context(calculation: &MapCalculation) -> HashMapContext {
+    let pp = &calculation.pp;
+    let parsed = calculation
+        .parsed
+        .expect("maps are always read when using a metric expression");
+    let attributes = parsed.attributes().mods(calculation.mods).build();
+
+    let values = [
+        pp.pp_aim,
+        pp.pp_speed,
+        pp.pp_flashlight,
+        pp.difficulty.stars,
+        parsed.bpm() * attributes.clock_rate,
+        calculation.map.total_time as f64 / 1000f64 / attributes.clock_rate,
+        attributes.ar,
+        attributes.od,
+        attributes.cs,
+        pp.difficulty.max_combo as f64,
+    ];
+
+    let mut context = HashMapContext::new();
+    for (variable, value) in METRIC_VARIABLES.into_iter().zip(values) {
+        context
+            .set_value(variable.to_string(), Value::from_float(value))
+            .unwrap();
+    }
+
+    context
+}
+
+/// Returns every collection that was added or merged into, with its size afterwards.
+fn add_new_collections(
+    args: &Args,
+    aim_ratio_groups: &BTreeMap<BucketKey, Vec<GroupedMap>>,
+    collections: &mut CollectionList,
+) -> Vec<CollectionSize> {
+    let mut new_collections = Vec::new();
+    let mut sizes = Vec::new();
+    let mut collisions = 0;
+    // Everything still in collection.db after the cleanup can collide: manual collections, and
+    // generated ones kept by --no-clean, --preserve or --only-buckets. So can two names of this run.
+    let taken: HashSet<String> = collections
+        .collections
+        .iter()
+        .filter_map(|c| c.name.clone())
+        .collect();
+    let mut produced: HashSet<String> = HashSet::new();
+
+    let mut aim_ratio_groups: Vec<(&BucketKey, &Vec<GroupedMap>)> =
+        aim_ratio_groups.iter().collect();
+    // Labels with an order come first, the rest stays in the order of the buckets.
+    if let Some(labels) = &args.labels {
+        aim_ratio_groups.sort_by_key(|(key, _)| {
+            let order = labels
+                .find(key.bucket as f64 * bucket_precision(args))
+                .and_then(|(_, label)| label.order);
+            (order.is_none(), order)
+        });
+    }
+
+    for (&key, maps) in aim_ratio_groups {
+        let mut collection_name = collection_name(args, key, maps);
+        let hashes = maps.iter().map(|m| m.map.hash.clone());
+
+        // A generated collection of the same name is merged into with --merge, not collided with.
+        let merge_target = args.merge
+            && has_collection_prefix(&collection_name, &args.collection_prefix)
+            && !produced.contains(&collection_name);
+        if produced.contains(&collection_name)
+            || (!merge_target && taken.contains(&collection_name))
+        {
+            collisions += 1;
+            match args.on_name_collision {
+                NameCollision::Skip => {
+                    status!(
+                        "Skipping {collection_name}, a collection with that name already exists"
+                    );
+                    continue;
+                }
+                NameCollision::Rename => {
+                    let renamed = (2..)
+                        .map(|n| format!("{collection_name} ({n})"))
+                        .find(|name| !taken.contains(name) && !produced.contains(name))
+                        .unwrap();
+                    status!("Renaming {collection_name} to {renamed}, a collection with that name already exists");
+                    collection_name = renamed;
+                }
+                NameCollision::Warn => {
+                    problem!("Warning: a collection named {collection_name} already exists, adding a second one");
+                }
+            }
+        }
+
+        produced.insert(collection_name.clone());
+
+        // Only generated collections are merged into, the manual ones have to stay untouched.
+        let existing = match args.merge {
+            true => collections.collections.iter_mut().find(|c| {
+                c.name.as_deref().is_some_and(|name| {
+                    name == collection_name && has_collection_prefix(name, &args.collection_prefix)
+                })
+            }),
+            false => None,
+        };
+
+        if let Some(existing) = existing {
+            let mut pruned = 0;
+            if args.prune_merge {
+                let current: HashSet<&str> =
+                    maps.iter().filter_map(|m| m.map.hash.as_deref()).collect();
+                let count = existing.beatmap_hashes.len();
+                existing
+                    .beatmap_hashes
+                    .retain(|hash| hash.as_deref().is_some_and(|h| current.contains(h)));
+                pruned = count - existing.beatmap_hashes.len();
+            }
+
+            let previous_count = existing.beatmap_hashes.len();
+            for hash in hashes {
+                if !existing.beatmap_hashes.contains(&hash) {
+                    existing.beatmap_hashes.push(hash);
+                }
+            }
+
+            if !shows_changes(args) {
+                let added = existing.beatmap_hashes.len() - previous_count;
+                match args.prune_merge {
+                    true => status!("Merging {added} new maps into {collection_name} and removing {pruned} maps that no longer belong there"),
+                    false => status!("Merging {added} new maps into {collection_name}"),
+                }
+            }
+            sizes.push(CollectionSize {
+                name: collection_name,
+                maps: existing.beatmap_hashes.len(),
+                key: Some(key),
+            });
+            continue;
+        }
+
+        if !shows_changes(args) {
+            status!("Adding {collection_name} with {} maps", maps.len());
+        }
+
+        sizes.push(CollectionSize {
+            name: collection_name.clone(),
+            maps: maps.len(),
+            key: Some(key),
+        });
+        new_collections.push(Collection {
+            name: Some(collection_name),
+            beatmap_hashes: hashes.collect(),
+        });
+    }
+
+    if collisions > 0 {
+        status!("Found {collisions} collections with the same name as an existing collection");
+    }
+
+    match args.collection_order {
+        CollectionOrder::Start => {
+            collections.collections.splice(0..0, new_collections);
+        }
+        CollectionOrder::End => collections.collections.extend(new_collections),
+    }
+
+    sizes
+}
+
+fn collection_name(args: &Args, key: BucketKey, maps: &[GroupedMap]) -> String {
+    let name = bucket_name(args, key);
+    if !args.name_include_stars {
+        return name;
+    }
+
+    // The range goes at the very end so the name still starts with the prefix and bucket label.
+    let stars = maps.iter().map(|m| m.stars);
+    let min = stars.clone().fold(f64::INFINITY, f64::min);
+    let max = stars.fold(f64::NEG_INFINITY, f64::max);
+    format!("{name} ({min:.1}★-{max:.1}★)")
+}
+
+fn bucket_name(args: &Args, key: BucketKey) -> String {
+    // Only label the mods when they were chosen explicitly, so the default names stay the same.
+    let mut prefix = args.collection_prefix.clone();
+    if let Some(mode) = args.mode {
+        prefix = format!("{prefix}{} ", mode.name());
+    }
+    if !args.mods.is_empty() {
+        prefix = format!("{prefix}{} ", mods::name(key.mods));
+    }
+    if let Some(name) = &args.single_collection {
+        return format!("{prefix}{name}");
+    }
+    let lower = key.bucket as f64 * bucket_precision(args);
+    if let Some((_, label)) = args.labels.as_ref().and_then(|labels| labels.find(lower)) {
+        return match key.star_band {
+            Some(band) => {
+                let stars = ((band as f64 * args.star_precision) * 100.0).round() / 100.0;
+                format!("{prefix}{stars}★ | {}", label.name)
+            }
+            None => format!("{prefix}{}", label.name),
+        };
+    }
+    let upper = (key.merged_through.unwrap_or(key.bucket) + 1) as f64 * bucket_precision(args);
+    // Merged buckets show their whole range where the name is usually only the lower bound.
+    let percent = |lower: f64, upper: f64| match key.merged_through {
+        Some(_) => format!(
+            "{}%-{}%",
+            format_percent(args, lower),
+            format_percent(args, upper)
+        ),
+        None => format!("{}%", format_percent(args, lower)),
+    };
+    match (&args.metric_expr, args.group_by) {
+        (Some(expr), _) => format!(
+            "{prefix}{expr} {}-{}",
+            format_bound(lower, 0),
+            format_bound(upper, 0)
+        ),
+        (None, GroupBy::Sv) => format!(
+            "{prefix}SV {}x-{}x",
+            format_bound(lower, 1),
+            format_bound(upper, 1)
+        ),
+        (None, GroupBy::Density) => format!(
+            "{prefix}Dense ({}-{} NPS)",
+            format_bound(lower, 0),
+            format_bound(upper, 0)
+        ),
+        (None, GroupBy::MissSensitivity) => format!(
+            "{prefix}1 Miss costs {}-{}% pp",
+            format_bound(lower, 0),
+            format_bound(upper, 0)
+        ),
+        (None, GroupBy::Acc) => format!("{prefix}{} Acc", percent(lower, upper)),
+        (None, GroupBy::LengthBand) => format!("{prefix}{}", length_band_name(args, key.bucket)),
+        (None, GroupBy::DensityCurveShape) => {
+            format!("{prefix}{}", CURVE_SHAPE_NAMES[key.bucket as usize])
+        }
+        (None, GroupBy::Cs) if groups_by_keys(args) => match key.merged_through {
+            Some(through) => format!("{prefix}{}K-{through}K", key.bucket),
+            None => format!("{prefix}{}K", key.bucket),
+        },
+        (None, GroupBy::Cs) => format!(
+            "{prefix}CS {}-{}",
+            format_bound(lower, 1),
+            format_bound(upper, 1)
+        ),
+        (None, GroupBy::DiffSpread) => format!(
+            "{prefix}Spread {}-{}★",
+            format_bound(lower, 1),
+            format_bound(upper, 1)
+        ),
+        (None, GroupBy::FirstSeconds) => format!(
+            "{prefix}First {}s {}-{}★",
+            args.first_seconds,
+            format_bound(lower, 1),
+            format_bound(upper, 1)
+        ),
+        (None, GroupBy::Aim) => {
+            let aim_ratio = percent(lower, upper);
+            match args.ratio_denominator {
+                RatioDenominator::AimSpeed => {
+                    let tapping = match key.merged_through {
+                        Some(_) => format!(
+                            "{}%-{}%",
+                            format_tapping_percent(args, upper),
+                            format_tapping_percent(args, lower)
+                        ),
+                        None => format!("{}%", format_tapping_percent(args, lower)),
+                    };
+                    format!("{prefix}{aim_ratio} Aim / {tapping} Tapping")
+                }
+                RatioDenominator::Total => format!("{prefix}{aim_ratio} Aim of total pp"),
+            }
+        }
+        (None, GroupBy::StarsAndRatio) => {
+            let stars = key.star_band.unwrap_or_default() as f64 * args.star_precision;
+            let stars = (stars * 100.0).round() / 100.0;
+            let aim_ratio = percent(lower, upper);
+            match args.ratio_denominator {
+                RatioDenominator::AimSpeed => format!("{prefix}{stars}★ | {aim_ratio} Aim"),
+                RatioDenominator::Total => {
+                    format!("{prefix}{stars}★ | {aim_ratio} Aim of total pp")
+                }
+            }
+        }
+    }
+}
+
+/// Formats a percentage in a collection name with `--name-decimals` decimal places. Without
+/// decimals the value is cut off instead of rounded, so a bucket is named after its lower bound.
+fn format_percent(args: &Args, percent: f64) -> String {
+    match args.name_decimals {
+        0 => (percent as i32).to_string(),
+        decimals => format!("{percent:.decimals$}"),
+    }
+}
+
+/// A bucket bound without the float noise of multiplying with the precision, eg. `0.3` instead of
+/// `0.30000000000000004`. Any precision with up to 6 decimals keeps all of them, and there are at
+/// least `min_decimals` so `CS 4.0-4.5` doesn't turn into `CS 4-4.5`.
+fn format_bound(bound: f64, min_decimals: usize) -> String {
+    let rounded = (bound * 1e6).round() / 1e6;
+    let decimals = rounded
+        .to_string()
+        .split_once('.')
+        .map_or(0, |(_, decimals)| decimals.len());
+    format!("{rounded:.*}", decimals.max(min_decimals))
+}
+
+/// The tapping share next to an aim percentage. Without decimals it is taken from the cut off aim
+/// value, so the two always add up to 100 like the names from before `--name-decimals`.
+fn format_tapping_percent(args: &Args, aim_percent: f64) -> String {
+    match args.name_decimals {
+        0 => (100 - aim_percent as i32).to_string(),
+        _ => format_percent(args, 100.0 - aim_percent),
+    }
+}
+
+/// Never write an older format version than the one that was read. If the client that wrote
+/// osu!.db is newer than the collection.db, bump the collection.db to match it.
+fn upgrade_collection_version(listing_version: u32, collections: &mut CollectionList) {
+    status!("Detected collection.db version {}", collections.version);
+
+    if listing_version > collections.version {
+        status!(
+            "Upgrading collection.db version from {} to {}",
+            collections.version,
+            listing_version
+        );
+        collections.version = listing_version;
+    }
+}
+
+/// Collapses whitespace at the end of the prefix into a single space, so `%` and `%  ` don't both
+/// look like `% ` in game while matching different collections. An empty prefix would match every
+/// collection, including the ones made by hand, so it has to be allowed explicitly.
+fn normalize_prefix(prefix: &str, allow_empty: bool) -> String {
+    let trimmed = prefix.trim_end();
+    if trimmed.is_empty() && !allow_empty {
+        panic!("--collection-prefix is empty, which would remove every collection. Pass --allow-empty-prefix --yes if that is really what you want");
+    }
+
+    match trimmed.len() < prefix.len() && !trimmed.is_empty() {
+        true => format!("{trimmed} "),
+        false => trimmed.to_string(),
+    }
+}
+
+/// Whether a collection name starts with the prefix. Names are matched as whole characters, and
+/// a match that would cut off combining marks, variation selectors, skin tones or zero width
+/// joiners (eg. prefix `🔥` against `🔥\u{fe0f} tech`) doesn't count, so emoji and accented
+/// names are only treated as managed when they actually look like the prefix.
+fn has_collection_prefix(name: &str, prefix: &str) -> bool {
+    let Some(rest) = name.strip_prefix(prefix) else {
+        return false;
+    };
+
+    !rest.chars().next().is_some_and(continues_character)
+}
+
+/// Whether a collection is removed before adding the new ones, by `--clean-regex` when it is set
+/// and by the prefix otherwise.
+fn is_cleaned(args: &Args, name: &str) -> bool {
+    match &args.clean_regex {
+        Some(regex) => regex.is_match(name),
+        None => has_collection_prefix(name, &args.collection_prefix),
+    }
+}
+
+/// Refuses a `--clean-regex` that would remove collections without the prefix unless `--yes` is
+/// passed, the same as an empty prefix. Those collections count as managed, so nothing else would
+/// stop eg. `.*` from removing all of them.
+fn check_clean_regex(args: &Args, collections: &CollectionList) {
+    let Some(regex) = &args.clean_regex else {
+        return;
+    };
+    if args.yes {
+        return;
+    }
+
+    let unprefixed: Vec<&str> = collections
+        .collections
+        .iter()
+        .filter_map(|c| c.name.as_deref())
+        .filter(|name| {
+            regex.is_match(name) && !has_collection_prefix(name, &args.collection_prefix)
+        })
+        .collect();
+    if !unprefixed.is_empty() {
+        panic!(
+            "--clean-regex would also remove {} collection(s) without the prefix ({}). Pass --yes if that is really what you want",
+            unprefixed.len(),
+            unprefixed.join(", ")
+        );
+    }
+}
+
+/// Whether a collection belongs to the tool, either because it has the prefix or because
+/// `--clean-regex` is allowed to remove it.
+fn is_managed(args: &Args, name: &str) -> bool {
+    has_collection_prefix(name, &args.collection_prefix)
+        || args
+            .clean_regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(name))
+}
+
+/// Characters that attach to the one before them instead of standing on their own.
+fn continues_character(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036f}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{200d}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{fe00}'..='\u{fe0f}'
+            | '\u{fe20}'..='\u{fe2f}'
+            | '\u{1f3fb}'..='\u{1f3ff}'
+            | '\u{e0020}'..='\u{e007f}'
+            | '\u{e0100}'..='\u{e01ef}'
+    )
+}
+
+/// The added and removed maps are printed instead of the collections that were added and removed.
+fn shows_changes(args: &Args) -> bool {
+    args.report_only_changes || args.dry_run
+}
+
+/// Returns how many collections were removed. With `--only-buckets`, only the collections named
+/// after one of those buckets are removed. The generated collections in `merged_into` are kept
+/// for `--merge`.
+fn remove_previous_collections(
+    args: &Args,
+    only_buckets: Option<&HashSet<i32>>,
+    mod_combinations: &[u32],
+    merged_into: &HashSet<String>,
+    collections: &mut CollectionList,
+) -> usize {
+    let bucket_names: Option<Vec<String>> = only_buckets.map(|buckets| {
+        mod_combinations
+            .iter()
+            .flat_map(|&mods| {
+                buckets.iter().map(move |&bucket| BucketKey {
+                    mods,
+                    star_band: None,
+                    bucket,
+                    merged_through: None,
+                })
+            })
+            .map(|key| bucket_name(args, key))
+            .collect()
+    });
+
+    let collection_count = collections.collections.len();
+    collections.collections.retain(|c| {
+        if let Some(name) = &c.name {
+            if !is_cleaned(args, name) {
+                return true;
+            }
+            if let Some(bucket_names) = &bucket_names {
+                // The leftovers are created again on every run with --leftovers.
+                if leftovers_name(args).as_deref() != Some(name.as_str())
+                    && !bucket_names
+                        .iter()
+                        .any(|bucket_name| is_named_after(name, bucket_name))
+                {
+                    return true;
+                }
+            }
+            if args.preserve.contains(name) {
+                status!("Preserving {name}");
+                return true;
+            }
+            if merged_into.contains(name) && has_collection_prefix(name, &args.collection_prefix) {
+                return true;
+            }
+            false
+        } else {
+            true
+        }
+    });
+
+    let removed = collection_count - collections.collections.len();
+    if !shows_changes(args) {
+        status!("Removed {removed} collections from previous iteration");
+    }
+    removed
+}
+
+fn leftovers_name(args: &Args) -> Option<String> {
+    let name = args.leftovers.as_ref()?;
+    Some(format!("{}{name}", args.collection_prefix))
+}
+
+/// Adds the collection of --leftovers. It replaces the previous one like the bucket collections,
+/// but isn't merged into with --merge, since the maps in it aren't sorted anywhere yet.
+fn add_leftovers(
+    args: &Args,
+    name: String,
+    leftovers: &[&Beatmap],
+    collections: &mut CollectionList,
+) -> CollectionSize {
+    if !shows_changes(args) {
+        status!("Adding {name} with {} maps", leftovers.len());
+    }
+
+    let collection = Collection {
+        name: Some(name.clone()),
+        beatmap_hashes: leftovers.iter().map(|map| map.hash.clone()).collect(),
+    };
+    match args.collection_order {
+        CollectionOrder::Start => collections.collections.insert(0, collection),
+        CollectionOrder::End => collections.collections.push(collection),
+    }
+
+    CollectionSize {
+        name,
+        maps: leftovers.len(),
+        key: None,
+    }
+}
+
+/// Whether a collection name is the bucket name, maybe followed by the star range of
+/// `--name-include-stars` or the number `--on-name-collision rename` adds.
+fn is_named_after(name: &str, bucket_name: &str) -> bool {
+    name.strip_prefix(bucket_name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(" ("))
+}
+
+/// The collections that weren't created by the tool. These have to be written back exactly as
+/// they were read, in the same order and with the same maps.
+fn manual_collections(args: &Args, collections: &CollectionList) -> Vec<Collection> {
+    collections
+        .collections
+        .iter()
+        .filter(|c| c.name.as_deref().is_none_or(|name| !is_managed(args, name)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use osu_db::listing::{Grade, RankedStatus};
+    use std::ffi::OsStr;
+
+    fn args(extra: &[&str]) -> Args {
+        args_in(Path::new("/nonexistent-osu-dir"), extra)
+    }
+
+    fn args_in(osu_dir: &Path, extra: &[&str]) -> Args {
+        let base = [OsStr::new("aim_ratio_collections"), osu_dir.as_os_str()];
+        Args::parse_from(base.into_iter().chain(extra.iter().map(OsStr::new)))
+    }
+
+    /// A standard map with a nomod star rating, like osu!.db lists it.
+    fn beatmap(hash: &str, stars: f64) -> Beatmap {
+        Beatmap {
+            artist_ascii: Some("Artist".to_string()),
+            artist_unicode: None,
+            title_ascii: Some(hash.to_string()),
+            title_unicode: None,
+            creator: None,
+            difficulty_name: Some("Insane".to_string()),
+            audio: None,
+            hash: Some(hash.to_string()),
+            file_name: Some(format!("{hash}.osu")),
+            status: RankedStatus::Ranked,
+            hitcircle_count: 0,
+            slider_count: 0,
+            spinner_count: 0,
+            last_modified: Default::default(),
+            approach_rate: 9.0,
+            circle_size: 4.0,
+            hp_drain: 5.0,
+            overall_difficulty: 8.0,
+            slider_velocity: 1.4,
+            std_ratings: vec![(ModSet(0), stars)],
+            taiko_ratings: Vec::new(),
+            ctb_ratings: Vec::new(),
+            mania_ratings: Vec::new(),
+            drain_time: 120,
+            total_time: 125_000,
+            preview_time: 0,
+            timing_points: Vec::new(),
+            beatmap_id: 1,
+            beatmapset_id: 1,
+            thread_id: 0,
+            std_grade: Grade::Unplayed,
+            taiko_grade: Grade::Unplayed,
+            ctb_grade: Grade::Unplayed,
+            mania_grade: Grade::Unplayed,
+            local_beatmap_offset: 0,
+            stack_leniency: 0.7,
+            mode: Mode::Standard,
+            song_source: None,
+            tags: None,
+            online_offset: 0,
+            title_font: None,
+            last_played: None,
+            is_osz2: false,
+            folder_name: Some("folder".to_string()),
+            last_online_check: Default::default(),
+            ignore_sounds: false,
+            ignore_skin: false,
+            disable_storyboard: false,
+            disable_video: false,
+            visual_override: false,
+            mysterious_short: None,
+            mysterious_last_modified: 0,
+            mania_scroll_speed: 0,
+        }
+    }
+
+    /// A small .osu file of `notes` circles `gap` milliseconds apart, going back and forth between
+    /// two points `distance` pixels apart. Far apart makes jumps, close together and quick streams.
+    pub(crate) fn osu_file(mode: u8, notes: usize, distance: i32, gap: f64) -> String {
+        let mut file = format!(
+            "osu file format v14\n\n[General]\nAudioFilename: audio.mp3\nMode: {mode}\n\n\
+             [Metadata]\nTitle:Test\nArtist:Test\nCreator:test\nVersion:Test\n\n\
+             [Difficulty]\nHPDrainRate:5\nCircleSize:4\nOverallDifficulty:8\nApproachRate:9\n\
+             SliderMultiplier:1.4\nSliderTickRate:1\n\n\
+             [TimingPoints]\n0,300,4,2,0,100,1,0\n\n[HitObjects]\n"
+        );
+        for note in 0..notes {
+            let x = 256 + (distance / 2) * if note % 2 == 0 { -1 } else { 1 };
+            let time = 1000.0 + note as f64 * gap;
+            file += &format!("{x},192,{time},1,0,0:0:0:0:\n");
+        }
+        file
+    }
+
+    /// Writes the file to `Songs/<folder>` in the osu! directory and returns how osu!.db lists it.
+    fn song(osu_dir: &Path, folder: &str, content: &str) -> Beatmap {
+        let hash = format!("{:x}", md5::compute(content));
+        let dir = osu_dir.join("Songs").join(folder);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{hash}.osu")), content).unwrap();
+        Beatmap {
+            folder_name: Some(folder.to_string()),
+            ..beatmap(&hash, 5.0)
+        }
+    }
+
+    fn listing(beatmaps: Vec<Beatmap>) -> Listing {
+        Listing {
+            version: 20231111,
+            folder_count: beatmaps.len() as u32,
+            unban_date: None,
+            player_name: None,
+            beatmaps,
+            user_permissions: 0,
+        }
+    }
+
+    fn key(mods: u32, bucket: i32) -> BucketKey {
+        BucketKey {
+            mods,
+            star_band: None,
+            bucket,
+            merged_through: None,
+        }
+    }
+
+    fn grouped(maps: &[Beatmap]) -> Vec<GroupedMap<'_>> {
+        maps.iter()
+            .map(|map| GroupedMap {
+                map,
+                stars: listed_stars(map, 0).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// An empty directory of its own for every test, so tests running in parallel don't share
+    /// files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "aim_ratio_collections_{name}_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn collection(name: &str, hashes: &[&str]) -> Collection {
+        Collection {
+            name: Some(name.to_string()),
+            beatmap_hashes: hashes.iter().map(|hash| Some(hash.to_string())).collect(),
+        }
+    }
+
+    fn collection_list(collections: Vec<Collection>) -> CollectionList {
+        CollectionList {
+            version: 20231111,
+            collections,
+        }
+    }
+
+    fn names(collections: &CollectionList) -> Vec<&str> {
+        collections
+            .collections
+            .iter()
+            .filter_map(|c| c.name.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn replacing_collection_db_keeps_a_backup() {
+        let dir = temp_dir("replace");
+        let path = dir.join("collection.db");
+        collection_list(vec![collection("old", &["a"])])
+            .to_file(&path)
+            .unwrap();
+
+        let new = collection_list(vec![collection("new", &["b"])]);
+        let written = write_temporary(&path, &new);
+        replace_collection_db(&path, &written);
+
+        let current = CollectionList::from_file(&path).unwrap();
+        let backup = CollectionList::from_file(dir.join("collection.db.bak")).unwrap();
+        assert_eq!(names(&current), ["new"]);
+        assert_eq!(names(&backup), ["old"]);
+        assert!(!written.exists());
+    }
+
+    #[test]
+    fn failed_write_leaves_collection_db_untouched() {
+        let dir = temp_dir("failed_write");
+        let path = dir.join("collection.db");
+        collection_list(vec![collection("old", &["a"])])
+            .to_file(&path)
+            .unwrap();
+        // A directory in place of the temporary file makes the write fail halfway through a run.
+        std::fs::create_dir(dir.join("collection.db.tmp")).unwrap();
+
+        let new = collection_list(vec![collection("new", &["b"])]);
+        let result = std::panic::catch_unwind(|| write_temporary(&path, &new));
+
+        assert!(result.is_err());
+        let current = CollectionList::from_file(&path).unwrap();
+        assert_eq!(names(&current), ["old"]);
+    }
+
+    #[test]
+    fn leftover_temporary_file_from_an_interrupted_run_is_replaced() {
+        let dir = temp_dir("interrupted");
+        let path = dir.join("collection.db");
+        collection_list(vec![collection("old", &["a"])])
+            .to_file(&path)
+            .unwrap();
+        // What a run that was killed in the middle of writing leaves behind.
+        std::fs::write(dir.join("collection.db.tmp"), [0x7f, 0x01]).unwrap();
+
+        let new = collection_list(vec![collection("new", &["b", "c"])]);
+        let written = write_temporary(&path, &new);
+        assert_eq!(names(&CollectionList::from_file(&path).unwrap()), ["old"]);
+
+        replace_collection_db(&path, &written);
+        let current = CollectionList::from_file(&path).unwrap();
+        assert_eq!(names(&current), ["new"]);
+        assert_eq!(current.collections[0].beatmap_hashes.len(), 2);
+    }
+
+    #[test]
+    fn aim_names_without_decimals_add_up_to_100() {
+        let whole = args(&["--ratio-precision", "2.5"]);
+        assert_eq!(bucket_name(&whole, key(0, 23)), "% 57% Aim / 43% Tapping");
+
+        let decimals = args(&["--ratio-precision", "2.5", "--name-decimals", "1"]);
+        assert_eq!(
+            bucket_name(&decimals, key(0, 23)),
+            "% 57.5% Aim / 42.5% Tapping"
+        );
+    }
+
+    #[test]
+    fn clean_regex_beyond_the_prefix_needs_yes() {
+        let collections = collection_list(vec![
+            collection("% 60% Aim / 40% Tapping", &["a"]),
+            collection("aim 50%", &["b"]),
+            collection("favourites", &["c"]),
+        ]);
+
+        check_clean_regex(&args(&["--clean-regex", "^% "]), &collections);
+        check_clean_regex(&args(&["--clean-regex", ".*", "--yes"]), &collections);
+        for regex in ["", ".*", "^(% |aim )"] {
+            let args = args(&["--clean-regex", regex]);
+            assert!(std::panic::catch_unwind(|| check_clean_regex(&args, &collections)).is_err());
+        }
+    }
+
+    #[test]
+    fn manual_collections_survive_a_run_unchanged() {
+        let args = args(&[]);
+        let mut collections = collection_list(vec![
+            collection("favourites", &["f1", "f2", "f3"]),
+            collection("% 60% Aim / 40% Tapping", &["old"]),
+            collection("tournament pool", &["t2", "t1"]),
+            collection("% 10% Aim / 90% Tapping", &["old2"]),
+            Collection {
+                name: None,
+                beatmap_hashes: vec![None, Some("unnamed".to_string())],
+            },
+            collection("zzz last", &[]),
+        ]);
+        let before = manual_collections(&args, &collections);
+
+        let maps = [beatmap("a", 5.0), beatmap("b", 6.0)];
+        let groups = BTreeMap::from([
+            (key(0, 6), grouped(&maps[..1])),
+            (key(0, 9), grouped(&maps[1..])),
+        ]);
+        remove_previous_collections(&args, None, &[0], &HashSet::new(), &mut collections);
+        add_new_collections(&args, &groups, &mut collections);
+
+        let mut bytes = Vec::new();
+        collections.to_writer(&mut bytes).unwrap();
+        let reloaded = CollectionList::from_bytes(&bytes).unwrap();
+
+        let after = manual_collections(&args, &reloaded);
+        assert_eq!(after.len(), before.len());
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!(before.name, after.name);
+            assert_eq!(before.beatmap_hashes, after.beatmap_hashes);
+        }
+        assert_eq!(
+            names(&reloaded),
+            [
+                "favourites",
+                "tournament pool",
+                "zzz last",
+                "% 60% Aim / 40% Tapping",
+                "% 90% Aim / 10% Tapping"
+            ]
+        );
+    }
+
+    #[test]
+    fn collections_kept_by_no_clean_are_renamed_around() {
+        let args = args(&["--no-clean"]);
+        let mut collections =
+            collection_list(vec![collection("% 60% Aim / 40% Tapping", &["old"])]);
+
+        let maps = [beatmap("a", 5.0)];
+        let groups = BTreeMap::from([(key(0, 6), grouped(&maps))]);
+        add_new_collections(&args, &groups, &mut collections);
+
+        assert_eq!(
+            names(&collections),
+            ["% 60% Aim / 40% Tapping", "% 60% Aim / 40% Tapping (2)"]
+        );
+    }
+
+    #[test]
+    fn same_name_twice_in_one_run_collides() {
+        // With a precision of 0.5 both buckets are named after the whole percent.
+        let args = args(&[
+            "--group-by",
+            "acc",
+            "--ratio-precision",
+            "0.5",
+            "--on-name-collision",
+            "skip",
+        ]);
+        let mut collections = collection_list(Vec::new());
+
+        let maps = [beatmap("a", 5.0), beatmap("b", 5.0)];
+        let groups = BTreeMap::from([
+            (key(0, 120), grouped(&maps[..1])),
+            (key(0, 121), grouped(&maps[1..])),
+        ]);
+        add_new_collections(&args, &groups, &mut collections);
+
+        assert_eq!(names(&collections), ["% 60% Acc"]);
+    }
+
+    #[test]
+    fn merge_targets_dont_collide() {
+        let args = args(&["--merge"]);
+        let mut collections =
+            collection_list(vec![collection("% 60% Aim / 40% Tapping", &["old"])]);
+
+        let maps = [beatmap("a", 5.0)];
+        let groups = BTreeMap::from([(key(0, 6), grouped(&maps))]);
+        add_new_collections(&args, &groups, &mut collections);
+
+        assert_eq!(names(&collections), ["% 60% Aim / 40% Tapping"]);
+        assert_eq!(collections.collections[0].beatmap_hashes.len(), 2);
+    }
+
+    #[test]
+    fn collection_db_version_survives_a_round_trip() {
+        let args = args(&[]);
+        let mut collections = collection_list(vec![
+            collection("favourites", &["f1", "f2"]),
+            collection("% 60% Aim / 40% Tapping", &["old"]),
+            collection("tournament pool", &["t1"]),
+        ]);
+        collections.version = 20240101;
+        let manual_bytes = |collections: &CollectionList| {
+            let manual = CollectionList {
+                version: collections.version,
+                collections: manual_collections(&args, collections),
+            };
+            let mut bytes = Vec::new();
+            manual.to_writer(&mut bytes).unwrap();
+            bytes
+        };
+        let before = manual_bytes(&collections);
+
+        // An older osu!.db doesn't downgrade the collection.db.
+        upgrade_collection_version(20231111, &mut collections);
+        let maps = [beatmap("a", 5.0)];
+        let groups = BTreeMap::from([(key(0, 7), grouped(&maps))]);
+        remove_previous_collections(&args, None, &[0], &HashSet::new(), &mut collections);
+        add_new_collections(&args, &groups, &mut collections);
+
+        let mut bytes = Vec::new();
+        collections.to_writer(&mut bytes).unwrap();
+        let reloaded = CollectionList::from_bytes(&bytes).unwrap();
+        assert_eq!(reloaded.version, 20240101);
+        assert_eq!(manual_bytes(&reloaded), before);
+
+        // A newer one upgrades it.
+        let mut collections = reloaded;
+        upgrade_collection_version(20250101, &mut collections);
+        assert_eq!(collections.version, 20250101);
+    }
+
+    #[test]
+    fn collections_come_out_in_the_same_order_on_every_run() {
+        let dir = temp_dir("ordering");
+        let maps = (0..8)
+            .map(|i| {
+                let content = osu_file(0, 200, 40 + i * 50, 60.0 + i as f64 * 30.0);
+                song(&dir, &i.to_string(), &content)
+            })
+            .collect();
+        let listing = listing(maps);
+        let args = args_in(&dir, &["--threads", "4", "--ratio-precision", "5"]);
+
+        let run = || {
+            let (groups, _) = group_maps_by(&args, None, &[0], &listing, None, None);
+            let expected: Vec<String> = groups.keys().map(|&key| bucket_name(&args, key)).collect();
+            let mut collections = collection_list(Vec::new());
+            add_new_collections(&args, &groups, &mut collections);
+            assert_eq!(names(&collections), expected);
+            collections.collections
+        };
+        let first = run();
+        assert!(first.len() > 1);
+        for _ in 0..3 {
+            assert_eq!(run(), first);
+        }
+
+        // With --per-set and the same star rating everywhere only the listing order is left.
+        let per_set = args_in(&dir, &["--per-set"]);
+        let maps: Vec<Beatmap> = (0..40)
+            .map(|i| Beatmap {
+                beatmapset_id: i / 2 + 1,
+                ..beatmap(&i.to_string(), 5.0)
+            })
+            .collect();
+        for _ in 0..3 {
+            let mut groups = BTreeMap::from([(key(0, 6), grouped(&maps))]);
+            collapse_beatmapsets(&per_set, &mut groups);
+            let kept: Vec<i32> = groups[&key(0, 6)]
+                .iter()
+                .map(|grouped| grouped.map.beatmapset_id)
+                .collect();
+            assert_eq!(kept, (1..=20).collect::<Vec<_>>());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn osu_directory_with_non_utf8_bytes_is_read() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = temp_dir("non_utf8").join(OsStr::from_bytes(b"osu\xff\xfe"));
+        let map = song(&dir, "1 Artist - Title", &osu_file(0, 200, 300, 300.0));
+        let listing = listing(vec![map]);
+        let args = args_in(&dir, &[]);
+
+        let (groups, _) = group_maps_by(&args, None, &[0], &listing, None, None);
+        assert_eq!(groups.values().map(Vec::len).sum::<usize>(), 1);
+        let exported = serde_json::to_value(&args).unwrap();
+        assert_eq!(exported["osu_path"], dir.to_string_lossy().as_ref());
+    }
+
+    #[test]
+    fn emoji_prefix_matches_whole_characters_and_survives_a_round_trip() {
+        assert!(has_collection_prefix("🔥 60% Aim", "🔥 "));
+        assert!(has_collection_prefix("🔥tech", "🔥"));
+        assert!(!has_collection_prefix("🔥\u{fe0f} tech", "🔥"));
+        assert!(!has_collection_prefix("👍🏽 hand picked", "👍"));
+        assert!(!has_collection_prefix("é maps", "e"));
+
+        let args = args(&["--collection-prefix", "🔥 "]);
+        let mut collections = collection_list(vec![
+            collection("🔥 60% Aim / 40% Tapping", &["old"]),
+            collection("🔥\u{fe0f} tech", &["t1"]),
+            collection("👨‍👩‍👧 family 𝄞", &["f1"]),
+        ]);
+        remove_previous_collections(&args, None, &[0], &HashSet::new(), &mut collections);
+
+        let mut bytes = Vec::new();
+        collections.to_writer(&mut bytes).unwrap();
+        let reloaded = CollectionList::from_bytes(&bytes).unwrap();
+        assert_eq!(names(&reloaded), ["🔥\u{fe0f} tech", "👨‍👩‍👧 family 𝄞"]);
+    }
+
+    #[test]
+    fn star_filter_uses_the_dt_star_rating_with_mods_dt() {
+        let dt = mods::parse("DT").unwrap();
+        let mut map = beatmap("a", 5.0);
+        map.std_ratings.push((ModSet(dt), 7.0));
+        assert_eq!(listed_stars(&map, 0), Some(5.0));
+        assert_eq!(listed_stars(&map, dt), Some(7.0));
+        assert_eq!(listed_stars(&map, mods::parse("HDDT").unwrap()), Some(7.0));
+        assert_eq!(listed_stars(&map, mods::parse("HR").unwrap()), None);
+
+        let dir = temp_dir("dt_stars");
+        let mut map = song(&dir, "1 Artist - Title", &osu_file(0, 200, 300, 300.0));
+        map.std_ratings.push((ModSet(dt), 7.0));
+        let listing = listing(vec![map]);
+        let nomod = args_in(&dir, &["--min-star-rating", "6"]);
+        let with_dt = args_in(&dir, &["--min-star-rating", "6", "--mods", "DT"]);
+
+        let (groups, _) = group_maps_by(&nomod, None, &[0], &listing, None, None);
+        assert!(groups.is_empty());
+        let (groups, _) = group_maps_by(&with_dt, None, &[dt], &listing, None, None);
+        assert_eq!(groups.values().map(Vec::len).sum::<usize>(), 1);
+        assert!(groups.keys().all(|key| key.mods == dt));
+    }
+
+    #[test]
+    fn maps_with_the_same_hash_are_only_kept_once() {
+        let args = args(&[]);
+        let first = Beatmap {
+            folder_name: Some("1 Artist - Title".to_string()),
+            ..beatmap("a", 5.0)
+        };
+        let copy = Beatmap {
+            folder_name: Some("1 Artist - Title (1)".to_string()),
+            ..beatmap("a", 5.0)
+        };
+        let other = beatmap("b", 5.0);
+        let unhashed = Beatmap {
+            hash: None,
+            ..beatmap("c", 5.0)
+        };
+
+        let kept =
+            remove_duplicate_hashes(&args, vec![&first, &copy, &other, &unhashed, &unhashed]);
+        // The first folder wins, maps without a hash can't be told apart and are all kept.
+        assert_eq!(kept.len(), 4);
+        assert!(std::ptr::eq(kept[0], &first));
+        assert!(std::ptr::eq(kept[1], &other));
+    }
+
+    #[test]
+    fn maps_of_another_mode_than_osu_db_says_are_counted() {
+        let dir = temp_dir("mode_mismatch");
+        let standard = song(&dir, "1 Artist - Jumps", &osu_file(0, 200, 300, 300.0));
+        // osu!.db still lists the map as standard after the .osu was changed to mania.
+        let mania = song(&dir, "2 Artist - Keys", &osu_file(3, 200, 300, 300.0));
+        let args = args_in(&dir, &[]);
+        let files = MapFiles {
+            io_limit: IoLimit::new(1),
+            archived: None,
+        };
+        let below_min_pp = AtomicUsize::new(0);
+
+        let processed = process_map(&args, None, &[0], &files, None, &below_min_pp, &mania);
+        assert!(processed.mode_mismatch);
+        assert!(processed.buckets.is_empty());
+        let processed = process_map(&args, None, &[0], &files, None, &below_min_pp, &standard);
+        assert!(!processed.mode_mismatch);
+        assert_eq!(processed.buckets.len(), 1);
+
+        let listing = listing(vec![standard, mania]);
+        let (groups, _) = group_maps_by(&args, None, &[0], &listing, None, None);
+        assert_eq!(groups.values().map(Vec::len).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn small_buckets_merge_in_order_into_the_smaller_neighbour() {
+        let args = args(&[]);
+        let maps: Vec<Beatmap> = (0..11).map(|i| beatmap(&i.to_string(), 5.0)).collect();
+        let mut groups = BTreeMap::from([
+            (key(0, 5), grouped(&maps[0..1])),
+            (key(0, 6), grouped(&maps[1..4])),
+            (key(0, 7), grouped(&maps[4..5])),
+            (key(0, 8), grouped(&maps[5..6])),
+            (key(0, 9), grouped(&maps[6..10])),
+            // Alone in its mod combination, so there is nothing to merge it into.
+            (key(64, 3), grouped(&maps[10..])),
+        ]);
+
+        merge_small_buckets(&args, 2, &mut groups);
+
+        let merged: Vec<_> = groups
+            .iter()
+            .map(|(key, maps)| (key.mods, key.bucket, key.merged_through, maps.len()))
+            .collect();
+        assert_eq!(
+            merged,
+            [
+                (0, 5, Some(6), 4),
+                (0, 7, Some(8), 2),
+                (0, 9, None, 4),
+                (64, 3, None, 1)
+            ]
+        );
+        let first = *groups.keys().next().unwrap();
+        assert_eq!(bucket_name(&args, first), "% 50%-70% Aim / 30%-50% Tapping");
+    }
+
+    /// Runs the cleanup and adding the way `main` does, returning what changed.
+    fn rerun(
+        args: &Args,
+        groups: BTreeMap<BucketKey, Vec<GroupedMap>>,
+        collections: &mut CollectionList,
+    ) -> Vec<changes::CollectionChange> {
+        let previous = changes::managed_collections(args, collections);
+        let merged_into: HashSet<String> = groups
+            .iter()
+            .map(|(&key, maps)| collection_name(args, key, maps))
+            .collect();
+        remove_previous_collections(args, None, &[0], &merged_into, collections);
+        add_new_collections(args, &groups, collections);
+        changes::diff(&previous, &changes::managed_collections(args, collections))
+    }
+
+    #[test]
+    fn merge_rerun_only_appends_the_new_maps() {
+        let maps: Vec<Beatmap> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|hash| beatmap(hash, 5.0))
+            .collect();
+        let hashes =
+            |collections: &CollectionList| collections.collections[1].beatmap_hashes.clone();
+
+        let merge = args(&["--merge"]);
+        let mut collections = collection_list(vec![
+            collection("favourites", &["f1"]),
+            collection("% 60% Aim / 40% Tapping", &["b", "a"]),
+        ]);
+        let changes = rerun(
+            &merge,
+            BTreeMap::from([(key(0, 6), grouped(&maps))]),
+            &mut collections,
+        );
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].kind == changes::ChangeKind::Changed);
+        assert_eq!(changes[0].added, ["c", "d"]);
+        assert!(changes[0].removed.is_empty());
+        let expected: Vec<_> = ["b", "a", "c", "d"].map(|h| Some(h.to_string())).into();
+        assert_eq!(hashes(&collections), expected);
+
+        // Nothing new, nothing changes.
+        let before = hashes(&collections);
+        let changes = rerun(
+            &merge,
+            BTreeMap::from([(key(0, 6), grouped(&maps))]),
+            &mut collections,
+        );
+        assert!(changes.is_empty());
+        assert_eq!(hashes(&collections), before);
+
+        let prune = args(&["--merge", "--prune-merge"]);
+        let changes = rerun(
+            &prune,
+            BTreeMap::from([(key(0, 6), grouped(&maps[1..]))]),
+            &mut collections,
+        );
+        assert_eq!(changes[0].removed, ["a"]);
+        assert!(changes[0].added.is_empty());
+        assert_eq!(
+            names(&collections),
+            ["favourites", "% 60% Aim / 40% Tapping"]
+        );
+    }
+
+    #[test]
+    fn metric_expr_names_have_no_float_noise() {
+        let args = args(&["--metric-expr", "aim / speed", "--ratio-precision", "0.1"]);
+        parse_metric_expr(args.metric_expr.as_deref().unwrap());
+        assert_eq!(bucket_name(&args, key(0, 3)), "% aim / speed 0.3-0.4");
+    }
+
+    #[test]
+    fn density_names_have_no_float_noise() {
+        let args = args(&["--group-by", "density", "--density-precision", "0.1"]);
+        assert_eq!(bucket_name(&args, key(0, 3)), "% Dense (0.3-0.4 NPS)");
+    }
+
+    #[test]
+    fn miss_sensitivity_names_have_no_float_noise() {
+        let args = args(&["--group-by", "miss-sensitivity", "--miss-precision", "0.7"]);
+        assert_eq!(bucket_name(&args, key(0, 3)), "% 1 Miss costs 2.1-2.8% pp");
+    }
+
+    #[test]
+    fn range_names_keep_every_decimal_of_the_precision() {
+        let cs = args(&["--group-by", "cs", "--cs-precision", "0.25"]);
+        assert_eq!(bucket_name(&cs, key(0, 17)), "% CS 4.25-4.5");
+        assert_eq!(bucket_name(&cs, key(0, 16)), "% CS 4.0-4.25");
+        let sv = args(&["--group-by", "sv", "--sv-precision", "0.1"]);
+        assert_eq!(bucket_name(&sv, key(0, 7)), "% SV 0.7x-0.8x");
+        let spread = args(&["--group-by", "diff-spread", "--spread-precision", "0.75"]);
+        assert_eq!(bucket_name(&spread, key(0, 3)), "% Spread 2.25-3.0★");
+        let first = args(&["--group-by", "first-seconds", "--star-precision", "0.25"]);
+        assert_eq!(bucket_name(&first, key(0, 21)), "% First 30s 5.25-5.5★");
+    }
+
+    #[test]
+    fn export_has_the_names_written_to_collection_db() {
+        let dir = temp_dir("export_names");
+        let path = dir.join("export.json");
+        let args = args(&["--leftovers", "Rest"]);
+        let maps = [beatmap("a", 5.0), beatmap("b", 6.0)];
+        let easy = beatmap("c", 2.0);
+        let groups = BTreeMap::from([(key(0, 6), grouped(&maps))]);
+        // Kept like with --no-clean, so the generated collection has to be renamed around it.
+        let mut collections = collection_list(vec![collection("% 60% Aim / 40% Tapping", &["x"])]);
+
+        let mut created = add_new_collections(&args, &groups, &mut collections);
+        let name = leftovers_name(&args).unwrap();
+        created.push(add_leftovers(&args, name, &[&easy], &mut collections));
+        export::write_collector(&path, &args, &groups, &created, &[&easy]);
+
+        let export: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let exported: Vec<(&str, usize)> = export["collections"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                let maps = c["beatmaps"].as_array().unwrap().len();
+                (c["name"].as_str().unwrap(), maps)
+            })
+            .collect();
+        assert_eq!(
+            exported,
+            [("% 60% Aim / 40% Tapping (2)", 2), ("% Rest", 1)]
+        );
+        assert_eq!(
+            &names(&collections)[1..],
+            ["% 60% Aim / 40% Tapping (2)", "% Rest"]
+        );
+    }
+
+    #[test]
+    fn mode_groups_its_own_maps_and_the_converts_with_include_converts() {
+        let dir = temp_dir("mode_taiko");
+        let taiko = Beatmap {
+            mode: Mode::Taiko,
+            std_ratings: Vec::new(),
+            taiko_ratings: vec![(ModSet(0), 5.0)],
+            ..song(&dir, "1 Artist - Taiko", &osu_file(1, 200, 0, 300.0))
+        };
+        let standard = Beatmap {
+            taiko_ratings: vec![(ModSet(0), 4.5)],
+            ..song(&dir, "2 Artist - Standard", &osu_file(0, 200, 300, 300.0))
+        };
+        let listing = listing(vec![taiko, standard]);
+        let folders = |args: &Args| {
+            let (groups, _) = group_maps_by(args, None, &[0], &listing, None, None);
+            let names: Vec<String> = groups.keys().map(|&key| bucket_name(args, key)).collect();
+            let folders: Vec<&str> = groups
+                .values()
+                .flatten()
+                .map(|grouped| grouped.map.folder_name.as_deref().unwrap())
+                .collect();
+            (names, folders)
+        };
+
+        let native = args_in(&dir, &["--mode", "taiko", "--group-by", "length-band"]);
+        assert_eq!(
+            folders(&native),
+            (
+                vec!["% Taiko Medium (1:30-3:00)".to_string()],
+                vec!["1 Artist - Taiko"]
+            )
+        );
+
+        let with_converts = args_in(
+            &dir,
+            &[
+                "--mode",
+                "taiko",
+                "--include-converts",
+                "--group-by",
+                "length-band",
+            ],
+        );
+        let (_, converted) = folders(&with_converts);
+        assert_eq!(converted, ["1 Artist - Taiko", "2 Artist - Standard"]);
+
+        let too_easy = args_in(
+            &dir,
+            &[
+                "--mode",
+                "taiko",
+                "--include-converts",
+                "--group-by",
+                "length-band",
+                "--min-star-rating",
+                "4.8",
+            ],
+        );
+        let (_, rated) = folders(&too_easy);
+        assert_eq!(rated, ["1 Artist - Taiko"]);
+    }
+
+    #[test]
+    fn mania_cs_groups_by_keycount_without_the_mod_adjustment() {
+        let dir = temp_dir("mania_keys");
+        let mania = Beatmap {
+            mode: Mode::Mania,
+            std_ratings: Vec::new(),
+            ..song(&dir, "1 Artist - Mania", &osu_file(3, 200, 0, 300.0))
+        };
+        let standard = song(&dir, "2 Artist - Standard", &osu_file(0, 200, 300, 300.0));
+        let listing = listing(vec![mania, standard]);
+        let hr = mods::parse("HR").unwrap();
+        let args = args_in(
+            &dir,
+            &[
+                "--mode",
+                "mania",
+                "--include-converts",
+                "--group-by",
+                "cs",
+                "--mods",
+                "HR",
+                "--min-star-rating",
+                "0",
+            ],
+        );
+
+        let (groups, _) = group_maps_by(&args, None, &[hr], &listing, None, None);
+        let names: Vec<(String, &str)> = groups
+            .iter()
+            .flat_map(|(&key, maps)| {
+                maps.iter()
+                    .map(move |grouped| (key, grouped.map.folder_name.as_deref().unwrap()))
+            })
+            .map(|(key, folder)| (bucket_name(&args, key), folder))
+            .collect();
+        // The native map has CS 4, the convert gets its keys from its OD and circle size.
+        assert_eq!(
+            names,
+            [
+                ("% Mania HR 4K".to_string(), "1 Artist - Mania"),
+                ("% Mania HR 7K".to_string(), "2 Artist - Standard"),
+            ]
+        );
+    }
 }
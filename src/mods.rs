@@ -0,0 +1,94 @@
+//! Parsing and naming of mod combinations passed to `--mods`.
+
+/// Acronyms and bits of the mods that can be combined. NC comes before DT so that nightcore, which
+/// always includes the DT bit, is only named NC.
+const MODS: [(&str, u32); 11] = [
+    ("NF", 1 << 0),
+    ("EZ", 1 << 1),
+    ("TD", 1 << 2),
+    ("HD", 1 << 3),
+    ("HR", 1 << 4),
+    ("SD", 1 << 5),
+    ("NC", 1 << 9 | 1 << 6),
+    ("DT", 1 << 6),
+    ("HT", 1 << 8),
+    ("FL", 1 << 10),
+    ("SO", 1 << 12),
+];
+
+/// The mods osu! calculates separate star ratings for in osu!.db.
+const STAR_RATING_MODS: u32 = 1 << 1 | 1 << 4 | 1 << 6 | 1 << 8;
+
+/// The combinations `--mods all` expands to.
+pub const ALL: [&str; 8] = ["NM", "EZ", "HD", "HR", "DT", "HDHR", "HDDT", "HRDT"];
+
+/// Parses a mod combination like `HDDT`. `NM` or an empty string mean no mods.
+pub fn parse(combination: &str) -> Result<u32, String> {
+    let combination = combination.to_uppercase();
+    if combination == "NM" {
+        return Ok(0);
+    }
+
+    let chars: Vec<char> = combination.chars().collect();
+    if !chars.len().is_multiple_of(2) {
+        return Err(format!("Invalid mod combination {combination}"));
+    }
+
+    chars
+        .chunks(2)
+        .map(|acronym| acronym.iter().collect::<String>())
+        .try_fold(0, |bits, acronym| {
+            MODS.iter()
+                .find(|(name, _)| *name == acronym)
+                .map(|(_, mod_bits)| bits | mod_bits)
+                .ok_or_else(|| format!("Unknown mod {acronym} in {combination}"))
+        })
+}
+
+pub fn name(mut bits: u32) -> String {
+    if bits == 0 {
+        return "NM".to_string();
+    }
+
+    let mut name = String::new();
+    for (acronym, mod_bits) in MODS {
+        if bits & mod_bits == mod_bits {
+            name += acronym;
+            bits &= !mod_bits;
+        }
+    }
+
+    name
+}
+
+/// The part of the combination that osu!.db has star ratings for, eg. `DT` for `HDDT` since
+/// hidden doesn't change the star rating. Nightcore is stored as DT.
+pub fn star_rating_bits(bits: u32) -> u32 {
+    bits & STAR_RATING_MODS
+}
+
+/// All mod combinations to calculate, in the order they were given and without duplicates.
+/// Without any mods given, only nomod is calculated.
+pub fn combinations(arguments: &[String]) -> Vec<u32> {
+    let mut combinations = Vec::new();
+
+    for argument in arguments {
+        let expanded = match argument.eq_ignore_ascii_case("all") {
+            true => ALL.to_vec(),
+            false => vec![argument.as_str()],
+        };
+
+        for combination in expanded {
+            let bits = parse(combination).unwrap_or_else(|why| panic!("{why}"));
+            if !combinations.contains(&bits) {
+                combinations.push(bits);
+            }
+        }
+    }
+
+    if combinations.is_empty() {
+        combinations.push(0);
+    }
+
+    combinations
+}
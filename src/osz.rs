@@ -0,0 +1,205 @@
+//! Reads maps straight from `.osz` archives for `--scan-osz`, without importing them into osu!
+//! first. The archives are regular zip files, every `.osu` file inside is read into memory and
+//! turned into an `osu!.db` style entry so the rest of the pipeline doesn't have to know where the
+//! maps came from.
+//!
+//! The hash of each map is the MD5 of the `.osu` bytes inside the archive, which is the same hash
+//! osu! assigns once the archive is imported, as long as the file isn't changed on import.
+
+use crate::output::{skipped, status};
+use osu_db::listing::{Beatmap, Grade, RankedStatus};
+use osu_db::{Listing, ModSet, Mode};
+use rosu_pp::BeatmapExt;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The maps found in the archives, with the contents of every `.osu` file by hash.
+pub struct ArchivedMaps {
+    pub listing: Listing,
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+pub fn scan(dir: &Path) -> ArchivedMaps {
+    let entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|why| panic!("Could not read {}: {why}", dir.display()));
+
+    let mut beatmaps = Vec::new();
+    let mut files = HashMap::new();
+    let mut archives = 0;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_osz = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("osz"));
+        if !is_osz {
+            continue;
+        }
+
+        let archive = File::open(&path)
+            .map_err(|why| why.to_string())
+            .and_then(|file| zip::ZipArchive::new(file).map_err(|why| why.to_string()));
+        let mut archive = match archive {
+            Ok(archive) => archive,
+            Err(why) => {
+                skipped!("Error while opening {}: {}", path.display(), why);
+                continue;
+            }
+        };
+        archives += 1;
+
+        let folder = path.file_stem().unwrap().to_string_lossy().into_owned();
+
+        for index in 0..archive.len() {
+            let mut file = match archive.by_index(index) {
+                Ok(file) => file,
+                Err(why) => {
+                    skipped!("Error while reading {}: {}", path.display(), why);
+                    continue;
+                }
+            };
+            if !file.name().to_lowercase().ends_with(".osu") {
+                continue;
+            }
+
+            let file_name = file.name().to_string();
+            let mut bytes = Vec::new();
+            if let Err(why) = file.read_to_end(&mut bytes) {
+                skipped!(
+                    "Error while reading {file_name} in {}: {why}",
+                    path.display()
+                );
+                continue;
+            }
+
+            let hash = format!("{:x}", md5::compute(&bytes));
+            match beatmap_entry(&bytes, &folder, &file_name, &hash) {
+                Some(beatmap) => {
+                    beatmaps.push(beatmap);
+                    files.insert(hash, bytes);
+                }
+                None => skipped!("Error while parsing {file_name} in {}", path.display()),
+            }
+        }
+    }
+
+    status!("Found {} maps in {archives} archives", beatmaps.len());
+
+    ArchivedMaps {
+        listing: Listing {
+            version: 0,
+            folder_count: archives,
+            unban_date: None,
+            player_name: None,
+            beatmaps,
+            user_permissions: 0,
+        },
+        files,
+    }
+}
+
+/// Builds the entry osu! would create for the map, from the metadata in the file and the parsed
+/// hit objects. The nomod star rating is calculated here for standard maps, so that
+/// `--min-star-rating` works the same as for imported maps.
+fn beatmap_entry(bytes: &[u8], folder: &str, file_name: &str, hash: &str) -> Option<Beatmap> {
+    let parsed = rosu_pp::Beatmap::from_bytes(bytes).ok()?;
+    let metadata = read_metadata(bytes);
+    let text = |key: &str| metadata.get(key).cloned();
+    let number = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(|value| value.parse::<f64>().ok())
+    };
+
+    let mode = Mode::from_raw(number("Mode").unwrap_or_default() as u8)?;
+    let first = parsed.hit_objects.first().map_or(0.0, |h| h.start_time);
+    let last = parsed.hit_objects.last().map_or(0.0, |h| h.start_time);
+    let drain = (last - first - parsed.total_break_time()).max(0.0);
+    let std_ratings = match mode {
+        Mode::Standard => vec![(ModSet(0), parsed.stars().calculate().stars())],
+        _ => Vec::new(),
+    };
+    let count = |kind: fn(&rosu_pp::parse::HitObject) -> bool| {
+        parsed.hit_objects.iter().filter(|h| kind(h)).count() as u16
+    };
+
+    Some(Beatmap {
+        artist_ascii: text("Artist"),
+        artist_unicode: text("ArtistUnicode").or_else(|| text("Artist")),
+        title_ascii: text("Title"),
+        title_unicode: text("TitleUnicode").or_else(|| text("Title")),
+        creator: text("Creator"),
+        difficulty_name: text("Version"),
+        audio: text("AudioFilename"),
+        hash: Some(hash.to_string()),
+        file_name: Some(file_name.to_string()),
+        status: RankedStatus::Unknown,
+        hitcircle_count: count(rosu_pp::parse::HitObject::is_circle),
+        slider_count: count(rosu_pp::parse::HitObject::is_slider),
+        spinner_count: count(rosu_pp::parse::HitObject::is_spinner),
+        last_modified: Default::default(),
+        approach_rate: parsed.ar,
+        circle_size: parsed.cs,
+        hp_drain: parsed.hp,
+        overall_difficulty: parsed.od,
+        slider_velocity: parsed.slider_mult,
+        std_ratings,
+        taiko_ratings: Vec::new(),
+        ctb_ratings: Vec::new(),
+        mania_ratings: Vec::new(),
+        drain_time: (drain / 1000.0) as u32,
+        total_time: last as u32,
+        preview_time: number("PreviewTime").unwrap_or_default() as u32,
+        timing_points: Vec::new(),
+        beatmap_id: number("BeatmapID").unwrap_or_default() as i32,
+        beatmapset_id: number("BeatmapSetID").unwrap_or(-1.0) as i32,
+        thread_id: 0,
+        std_grade: Grade::Unplayed,
+        taiko_grade: Grade::Unplayed,
+        ctb_grade: Grade::Unplayed,
+        mania_grade: Grade::Unplayed,
+        local_beatmap_offset: 0,
+        stack_leniency: number("StackLeniency").unwrap_or(0.7) as f32,
+        mode,
+        song_source: text("Source"),
+        tags: text("Tags"),
+        online_offset: 0,
+        title_font: None,
+        last_played: None,
+        is_osz2: false,
+        folder_name: Some(folder.to_string()),
+        last_online_check: Default::default(),
+        ignore_sounds: false,
+        ignore_skin: false,
+        disable_storyboard: false,
+        disable_video: false,
+        visual_override: false,
+        mysterious_short: None,
+        mysterious_last_modified: 0,
+        mania_scroll_speed: 0,
+    })
+}
+
+/// The `key: value` pairs of the General, Metadata and Difficulty sections.
+fn read_metadata(bytes: &[u8]) -> HashMap<String, String> {
+    let text = String::from_utf8_lossy(bytes);
+    let mut metadata = HashMap::new();
+    let mut in_section = false;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_section = matches!(line, "[General]" | "[Metadata]" | "[Difficulty]");
+            continue;
+        }
+        if !in_section {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            metadata.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    metadata
+}
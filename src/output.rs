@@ -0,0 +1,99 @@
+//! What is printed while running, chosen with `--output-format`. Progress and other status
+//! messages go through [`status!`] and are only printed as text, problems with single maps or
+//! files go through [`problem!`] and end up on stderr when stdout is reserved for the summary.
+//! Maps that are skipped because of such a problem go through [`skipped!`], which aborts the run
+//! instead with `--strict`. What `--explain` prints goes through [`detail!`], which is moved to
+//! stderr the same way.
+
+use crate::changes::CollectionChange;
+use crate::BucketKey;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Stops printing status messages, for everything except the text output.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Turns every skipped map into an error that ends the run, for `--strict`.
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+pub fn strict() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::output::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! problem {
+    ($($arg:tt)*) => {
+        match $crate::output::quiet() {
+            true => eprintln!($($arg)*),
+            false => println!($($arg)*),
+        }
+    };
+}
+
+/// Printed on request, so it is kept even when quiet, but not on stdout in front of the summary.
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        match $crate::output::quiet() {
+            true => eprintln!($($arg)*),
+            false => println!($($arg)*),
+        }
+    };
+}
+
+/// Exits right away with --strict, before anything is written. The other threads are still in the
+/// middle of their maps, but nothing they calculated would be used anyway.
+macro_rules! skipped {
+    ($($arg:tt)*) => {
+        match $crate::output::strict() {
+            true => {
+                eprintln!($($arg)*);
+                eprintln!("Aborting because of --strict, collection.db was not changed");
+                std::process::exit(1);
+            }
+            false => $crate::output::problem!($($arg)*),
+        }
+    };
+}
+
+pub(crate) use {detail, problem, skipped, status};
+
+/// Printed as JSON at the end of a run with `--output-format json`.
+#[derive(Serialize)]
+pub struct Summary {
+    pub total_maps: usize,
+    pub grouped_maps: usize,
+    pub removed_collections: usize,
+    pub collections: Vec<CollectionSize>,
+    /// Only there with `--report-only-changes` or `--dry-run`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<CollectionChange>>,
+    pub dry_run: bool,
+}
+
+/// A collection that was created or merged into, with the number of maps it has now.
+#[derive(Serialize)]
+pub struct CollectionSize {
+    pub name: String,
+    pub maps: usize,
+    /// The bucket the collection was made from, `None` for the leftovers.
+    #[serde(skip)]
+    pub key: Option<BucketKey>,
+}
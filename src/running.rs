@@ -0,0 +1,17 @@
+//! Detection of a running osu! stable client. osu! keeps the collections in memory and writes
+//! them back to `collection.db` when it closes, so anything written while it is open gets
+//! overwritten on exit.
+
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Whether an `osu!.exe` process is running, which is also what it is called under Wine. lazer
+/// doesn't use `collection.db`, so it doesn't count.
+pub fn osu_running() -> bool {
+    let mut system = System::new();
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::nothing());
+
+    system
+        .processes()
+        .values()
+        .any(|process| process.name().eq_ignore_ascii_case("osu!.exe"))
+}